@@ -0,0 +1,222 @@
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::{AnimationDecoder, DynamicImage, ImageFormat, ImageResult};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+
+use crate::phash::{Hash, ImageHasher};
+
+/// Consecutive frames closer than this many bits are the same shot and only the first is kept.
+/// This makes the sequence independent of frame rate and of frames held for several ticks.
+const KEYFRAME_DISTANCE: u32 = 4;
+
+/// The hashes of the keyframes of an animation, in order.
+#[derive(Debug, Clone)]
+pub struct SequenceHash {
+    pub frames: Vec<Hash>,
+}
+
+impl SequenceHash {
+    /// Parse a comma separated list of hashes, as printed by `Display`.
+    pub fn parse(text: &str) -> Option<SequenceHash> {
+        let frames: Option<Vec<Hash>> = text.split(',').map(Hash::parse).collect();
+        Some(SequenceHash { frames: frames? })
+    }
+
+    /// Number of frames that match in the same order in both sequences, where frames match if
+    /// they are within `threshold` bits. Frames dropped from either sequence are skipped over
+    /// rather than breaking the match, so this is the longest common subsequence.
+    pub fn matching_frames(&self, other: &SequenceHash, threshold: u32) -> usize {
+        let mut previous = vec![0; other.frames.len() + 1];
+        let mut current = vec![0; other.frames.len() + 1];
+
+        for frame in self.frames.iter() {
+            for (j, other_frame) in other.frames.iter().enumerate() {
+                current[j + 1] = if frame.distance(other_frame).is_some_and(|distance| distance <= threshold) {
+                    previous[j] + 1
+                } else {
+                    previous[j + 1].max(current[j])
+                };
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+
+        previous[other.frames.len()]
+    }
+
+    /// Fraction of the shorter sequence that matches the other, so an animation with frames
+    /// dropped still fully matches the original.
+    pub fn similarity(&self, other: &SequenceHash, threshold: u32) -> f32 {
+        let shorter = self.frames.len().min(other.frames.len());
+        if shorter == 0 {
+            return 0.0;
+        }
+
+        self.matching_frames(other, threshold) as f32 / shorter as f32
+    }
+}
+
+impl fmt::Display for SequenceHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frames: Vec<String> = self.frames.iter().map(|hash| hash.to_hex()).collect();
+        write!(f, "{}", frames.join(","))
+    }
+}
+
+/// Every frame of an animated GIF or APNG, composited onto the full canvas. Still images give a
+/// single frame so they can be matched against animations.
+pub fn frames(path: &Path) -> ImageResult<Vec<DynamicImage>> {
+    let format = image::io::Reader::open(path)?.with_guessed_format()?.format();
+
+    let frames = match format {
+        Some(ImageFormat::Gif) => {
+            GifDecoder::new(BufReader::new(File::open(path)?))?.into_frames().collect_frames()?
+        },
+        Some(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(BufReader::new(File::open(path)?))?;
+            if !decoder.is_apng() {
+                return Ok(vec![image::open(path)?]);
+            }
+            decoder.apng().into_frames().collect_frames()?
+        },
+        _ => return Ok(vec![image::open(path)?]),
+    };
+
+    Ok(frames.into_iter().map(|frame| DynamicImage::ImageRgba8(frame.into_buffer())).collect())
+}
+
+/// Hash every frame with `hasher` and keep the keyframes, where the picture changes.
+pub fn hash(frames: &[DynamicImage], hasher: &dyn ImageHasher) -> SequenceHash {
+    let mut keyframes: Vec<Hash> = Vec::new();
+
+    for frame in frames.iter() {
+        let hash = hasher.hash(frame);
+
+        match keyframes.last() {
+            Some(last) if last.distance(&hash).is_some_and(|distance| distance < KEYFRAME_DISTANCE) => {},
+            _ => keyframes.push(hash),
+        }
+    }
+
+    SequenceHash { frames: keyframes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 64 bit hash with the `i`th byte set, so distinct frames are 16 bits apart.
+    fn frame(i: usize) -> Hash {
+        let bits: Vec<bool> = (0..64).map(|bit| bit / 8 == i).collect();
+        Hash::from_bits(&bits)
+    }
+
+    fn sequence(indices: &[usize]) -> SequenceHash {
+        SequenceHash { frames: indices.iter().map(|i| frame(*i)).collect() }
+    }
+
+    #[test]
+    fn sequence_hash_round_trips_odd_lengths() {
+        let frames = vec![
+            Hash::from_bits(&[true; 49]),
+            Hash::from_bits(&[false, true, true, false, true, false, true]),
+        ];
+        let sequence = SequenceHash { frames: frames.clone() };
+
+        assert_eq!(SequenceHash::parse(&sequence.to_string()).unwrap().frames, frames);
+    }
+
+    #[test]
+    fn dropped_frames_still_fully_match() {
+        let original = sequence(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        let dropped = sequence(&[0, 1, 3, 4, 6, 7]);
+
+        assert_eq!(original.matching_frames(&dropped, 4), 6);
+        assert_eq!(dropped.matching_frames(&original, 4), 6);
+        assert_eq!(original.similarity(&dropped, 4), 1.0);
+    }
+
+    #[test]
+    fn duplicated_frames_match_once() {
+        let original = sequence(&[0, 1, 2, 3]);
+        let duplicated = sequence(&[0, 1, 1, 2, 2, 2, 3]);
+
+        assert_eq!(original.matching_frames(&duplicated, 4), 4);
+        assert_eq!(duplicated.matching_frames(&original, 4), 4);
+        assert_eq!(duplicated.similarity(&original, 4), 1.0);
+    }
+
+    #[test]
+    fn reordered_frames_only_match_in_order() {
+        let original = sequence(&[0, 1, 2, 3]);
+
+        assert_eq!(original.matching_frames(&sequence(&[0, 2, 1, 3]), 4), 3);
+        assert_eq!(original.similarity(&sequence(&[0, 2, 1, 3]), 4), 0.75);
+        assert_eq!(original.matching_frames(&sequence(&[3, 2, 1, 0]), 4), 1);
+        assert_eq!(original.similarity(&sequence(&[4, 5, 6, 7]), 4), 0.0);
+    }
+
+    #[test]
+    fn frames_within_the_threshold_match() {
+        let mut bits: Vec<bool> = (0..64).map(|bit| bit / 8 == 1).collect();
+        bits[0] = true;
+        bits[40] = true;
+        let noisy = SequenceHash { frames: vec![frame(0), Hash::from_bits(&bits), frame(2)] };
+        let original = sequence(&[0, 1, 2]);
+
+        assert_eq!(original.matching_frames(&noisy, 2), 3);
+        assert_eq!(original.matching_frames(&noisy, 1), 2);
+    }
+
+    #[test]
+    fn similarity_is_between_zero_and_one() {
+        let empty = SequenceHash { frames: Vec::new() };
+        let original = sequence(&[0, 1, 2, 3]);
+
+        assert_eq!(empty.matching_frames(&original, 4), 0);
+        assert_eq!(empty.similarity(&original, 4), 0.0);
+        assert_eq!(original.similarity(&empty, 4), 0.0);
+        assert_eq!(empty.similarity(&empty, 4), 0.0);
+
+        for other in [&[0, 0, 0, 0, 0, 0][..], &[3, 2, 1, 0, 1, 2, 3], &[7], &[1, 3, 5, 7, 0, 2]] {
+            let similarity = original.similarity(&sequence(other), 4);
+            assert!((0.0..=1.0).contains(&similarity), "{other:?} gave {similarity}");
+        }
+    }
+
+    #[test]
+    fn hashes_of_different_lengths_never_match() {
+        let short = SequenceHash { frames: vec![Hash::from_bits(&[false; 16])] };
+        let long = SequenceHash { frames: vec![Hash::from_bits(&[false; 64])] };
+
+        assert_eq!(short.matching_frames(&long, 64), 0);
+    }
+
+    /// Stripes at a different angle for each `pattern`, so every pattern hashes differently.
+    fn picture(pattern: u32) -> DynamicImage {
+        let angle = pattern as f32 * std::f32::consts::PI / 5.0;
+        DynamicImage::ImageLuma8(image::GrayImage::from_fn(64, 64, |x, y| {
+            let position = x as f32 * angle.cos() + y as f32 * angle.sin();
+            image::Luma([if (position / 8.0).rem_euclid(2.0) < 1.0 { 40 } else { 220 }])
+        }))
+    }
+
+    #[test]
+    fn held_frames_collapse_into_keyframes() {
+        let hasher = crate::phash::hasher("phash", 8).unwrap();
+        let frames: Vec<DynamicImage> = [0, 0, 0, 1, 1, 2, 3, 3].iter().map(|pattern| picture(*pattern)).collect();
+        let keyframes = hash(&frames, hasher.as_ref());
+
+        assert_eq!(keyframes.frames.len(), 4);
+
+        // Dropping every held copy and one of the shots still matches the whole shorter sequence
+        let dropped: Vec<DynamicImage> = [0, 1, 3].iter().map(|pattern| picture(*pattern)).collect();
+        let dropped = hash(&dropped, hasher.as_ref());
+
+        assert_eq!(keyframes.matching_frames(&dropped, 0), 3);
+        assert_eq!(keyframes.similarity(&dropped, 0), 1.0);
+    }
+}
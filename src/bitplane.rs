@@ -0,0 +1,79 @@
+use image::{DynamicImage, GenericImage, GrayImage, Luma, RgbaImage};
+
+pub const CHANNEL_NAMES: [&str; 4] = ["R", "G", "B", "A"];
+
+/// Number of channels worth looking at, skipping alpha if the image doesn't have any.
+pub fn channel_count(image: &DynamicImage) -> usize {
+    if image.color().has_alpha() { 4 } else { 3 }
+}
+
+/// Extract a single bit plane of a channel as a black and white image. `bit` 0 is the LSB.
+pub fn plane(rgba: &RgbaImage, channel: usize, bit: u8) -> GrayImage {
+    let mut plane = GrayImage::new(rgba.width(), rgba.height());
+
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        let value = ((pixel.0[channel] >> bit) & 1) * 255;
+        plane.put_pixel(x, y, Luma([value]));
+    }
+
+    plane
+}
+
+/// Lay every bit plane of every channel out in a grid, one row per channel with the MSB on the
+/// left.
+pub fn contact_sheet(image: &DynamicImage) -> GrayImage {
+    let (width, height) = (image.width(), image.height());
+    let channels = channel_count(image);
+    let rgba = image.to_rgba8();
+    let mut sheet = GrayImage::new(width * 8, height * channels as u32);
+
+    for channel in 0..channels {
+        for bit in 0..8u8 {
+            let column = (7 - bit) as u32;
+            sheet.copy_from(&plane(&rgba, channel, bit), column * width, channel as u32 * height)
+                .expect("Bit plane does not fit in the contact sheet");
+        }
+    }
+
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two pixels whose channels have a different bit set in each, so every channel and bit
+    /// index gives a different pattern.
+    fn pixels() -> RgbaImage {
+        RgbaImage::from_fn(2, 1, |x, _| if x == 0 {
+            image::Rgba([0b0000_0001, 0b0000_0100, 0b0001_0000, 0b1000_0000])
+        } else {
+            image::Rgba([0b1111_1110, 0b1111_1011, 0b1110_1111, 0b0111_1111])
+        })
+    }
+
+    #[test]
+    fn plane_extracts_each_channel_and_bit() {
+        let rgba = pixels();
+        let set_bits = [0, 2, 4, 7];
+
+        for (channel, set_bit) in set_bits.iter().enumerate() {
+            for bit in 0..8u8 {
+                let plane = plane(&rgba, channel, bit);
+                let expected = if bit == *set_bit { [255, 0] } else { [0, 255] };
+                assert_eq!([plane.get_pixel(0, 0).0[0], plane.get_pixel(1, 0).0[0]], expected, "channel {channel} bit {bit}");
+            }
+        }
+    }
+
+    #[test]
+    fn contact_sheet_puts_the_msb_on_the_left() {
+        let sheet = contact_sheet(&DynamicImage::ImageRgba8(pixels()));
+
+        assert_eq!(sheet.dimensions(), (16, 4));
+        // Alpha's MSB is set in the first pixel, red's LSB in the last column
+        assert_eq!(sheet.get_pixel(0, 3).0[0], 255);
+        assert_eq!(sheet.get_pixel(14, 0).0[0], 255);
+        assert_eq!(sheet.get_pixel(15, 0).0[0], 0);
+    }
+}
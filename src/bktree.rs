@@ -0,0 +1,174 @@
+use std::collections::BinaryHeap;
+
+use crate::phash::Hash;
+
+/// A node in the tree, with children keyed by their distance from this node's hash.
+struct Node<T> {
+    hash: Hash,
+    value: T,
+    children: Vec<(u32, usize)>,
+}
+
+/// Burkhard-Keller tree over Hamming distance. Lookups only visit subtrees whose distance from
+/// each node could possibly be within range, which avoids comparing every pair of hashes. Every
+/// hash in the tree and every hash looked up must be the same length.
+pub struct BkTree<T> {
+    nodes: Vec<Node<T>>,
+}
+
+fn distance(a: &Hash, b: &Hash) -> u32 {
+    a.distance(b).expect("Hashes in a BK-tree must all be the same length")
+}
+
+impl<T> BkTree<T> {
+    pub fn new() -> BkTree<T> {
+        BkTree { nodes: Vec::new() }
+    }
+
+    pub fn insert(&mut self, hash: Hash, value: T) {
+        let new_index = self.nodes.len();
+
+        if new_index > 0 {
+            let mut current = 0;
+            loop {
+                let distance = distance(&self.nodes[current].hash, &hash);
+                match self.nodes[current].children.iter().find(|(d, _)| *d == distance) {
+                    Some((_, child)) => current = *child,
+                    None => {
+                        self.nodes[current].children.push((distance, new_index));
+                        break;
+                    }
+                }
+            }
+        }
+
+        self.nodes.push(Node { hash, value, children: Vec::new() });
+    }
+
+    /// Every value whose hash is within `max_distance` of `hash`, along with that distance.
+    pub fn find(&self, hash: &Hash, max_distance: u32) -> Vec<(u32, &T)> {
+        let mut found = Vec::new();
+        if self.nodes.is_empty() {
+            return found;
+        }
+
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let distance = distance(&node.hash, hash);
+
+            if distance <= max_distance {
+                found.push((distance, &node.value));
+            }
+
+            // Triangle inequality: only children in this band can hold matches
+            for (child_distance, child) in node.children.iter() {
+                if child_distance.abs_diff(distance) <= max_distance {
+                    stack.push(*child);
+                }
+            }
+        }
+
+        found
+    }
+
+    /// The `k` values whose hashes are closest to `hash`, nearest first. Subtrees are skipped once
+    /// they can't beat the furthest of the best `k` found so far.
+    pub fn nearest(&self, hash: &Hash, k: usize) -> Vec<(u32, &T)> {
+        // Max-heap of (distance, index) so the worst of the current best is on top
+        let mut best: BinaryHeap<(u32, usize)> = BinaryHeap::new();
+        if self.nodes.is_empty() || k == 0 {
+            return Vec::new();
+        }
+
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let distance = distance(&node.hash, hash);
+
+            if best.len() < k {
+                best.push((distance, index));
+            } else if distance < best.peek().unwrap().0 {
+                best.pop();
+                best.push((distance, index));
+            }
+
+            let radius = if best.len() < k { u32::MAX } else { best.peek().unwrap().0 };
+            for (child_distance, child) in node.children.iter() {
+                if child_distance.abs_diff(distance) <= radius {
+                    stack.push(*child);
+                }
+            }
+        }
+
+        best.into_sorted_vec().into_iter().map(|(distance, index)| (distance, &self.nodes[index].value)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_hashes(count: usize) -> Vec<Hash> {
+        let mut rng = StdRng::seed_from_u64(34);
+        (0..count).map(|_| {
+            let bits: Vec<bool> = (0..64).map(|_| rng.gen_bool(0.5)).collect();
+            Hash::from_bits(&bits)
+        }).collect()
+    }
+
+    fn tree(hashes: &[Hash]) -> BkTree<usize> {
+        let mut tree = BkTree::new();
+        for (index, hash) in hashes.iter().enumerate() {
+            tree.insert(hash.clone(), index);
+        }
+        tree
+    }
+
+    #[test]
+    fn find_matches_a_linear_scan() {
+        let hashes = random_hashes(500);
+        let tree = tree(&hashes);
+
+        for query in hashes.iter().take(20) {
+            let mut found: Vec<(u32, usize)> = tree.find(query, 24).into_iter().map(|(d, i)| (d, *i)).collect();
+            found.sort();
+
+            let mut expected: Vec<(u32, usize)> = hashes.iter().enumerate()
+                .map(|(i, hash)| (distance(query, hash), i))
+                .filter(|(d, _)| *d <= 24)
+                .collect();
+            expected.sort();
+
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn nearest_matches_a_linear_scan() {
+        let hashes = random_hashes(500);
+        let tree = tree(&hashes);
+        let queries = random_hashes(520);
+
+        for query in queries[500..].iter() {
+            let found: Vec<u32> = tree.nearest(query, 5).into_iter().map(|(d, _)| d).collect();
+
+            let mut expected: Vec<u32> = hashes.iter().map(|hash| distance(query, hash)).collect();
+            expected.sort();
+            expected.truncate(5);
+
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn empty_tree_finds_nothing() {
+        let tree: BkTree<usize> = BkTree::new();
+        let query = Hash::from_bits(&[true; 64]);
+
+        assert!(tree.find(&query, 64).is_empty());
+        assert!(tree.nearest(&query, 3).is_empty());
+    }
+}
@@ -0,0 +1,434 @@
+use rayon::prelude::*;
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+
+use crate::image_f32::{ImageF32, ImageView};
+use crate::simd;
+
+// -------------------------------------------------------------------------------------------------
+// Kernels
+// -------------------------------------------------------------------------------------------------
+//
+// Kernels are applied as a correlation, the way image processing usually writes them: the kernel
+// is laid over the image without flipping, so a kernel with -1 on the left and 1 on the right
+// responds to brightness increasing to the right. The centre of the kernel is at `width / 2`,
+// `height / 2`.
+
+/// Kernels with more weights than this that can't be split into two 1D passes are applied with
+/// an FFT, whose cost doesn't grow with the kernel.
+const FFT_MIN_AREA: usize = 225;
+
+/// A kernel, remembering its two 1D factors if it has them so it can be applied in two cheap
+/// passes instead of one expensive one.
+#[derive(Debug, Clone)]
+pub struct Kernel {
+    pub weights: ImageF32,
+    /// Horizontal and vertical factors, whose outer product is `weights`
+    factors: Option<(Vec<f32>, Vec<f32>)>,
+}
+
+impl Kernel {
+    /// Wrap a 2D kernel, checking whether it can be split into two 1D passes.
+    pub fn new(weights: ImageF32) -> Kernel {
+        let factors = factorize(&weights);
+        Kernel { weights, factors }
+    }
+
+    /// Build a kernel from row-major weights.
+    pub fn from_rows(width: usize, height: usize, weights: Vec<f32>) -> Kernel {
+        Kernel::new(ImageF32::from_vec(width, height, weights))
+    }
+
+    /// The outer product of a horizontal and a vertical 1D kernel.
+    pub fn separable(horizontal: Vec<f32>, vertical: Vec<f32>) -> Kernel {
+        let weights = ImageF32::from_fn(horizontal.len(), vertical.len(), |x, y| horizontal[x] * vertical[y]);
+        Kernel { weights, factors: Some((horizontal, vertical)) }
+    }
+
+    /// Normalized Gaussian out to `radius` pixels either side. A sigma of 0 or less, which
+    /// would divide by zero, is taken as its limit: all the weight on the centre, i.e. no blur.
+    pub fn gaussian(sigma: f32, radius: usize) -> Kernel {
+        let weights: Vec<f32> = (0..2 * radius + 1)
+            .map(|i| {
+                let x = i as f32 - radius as f32;
+                if sigma <= 0.0 {
+                    return if i == radius { 1.0 } else { 0.0 };
+                }
+                (-(x * x) / (2.0 * sigma * sigma)).exp()
+            })
+            .collect();
+        let sum: f32 = weights.iter().sum();
+        let weights: Vec<f32> = weights.iter().map(|weight| weight / sum).collect();
+
+        Kernel::separable(weights.clone(), weights)
+    }
+
+    /// Mean of a `size` by `size` square.
+    pub fn box_filter(size: usize) -> Kernel {
+        let weights = vec![1.0 / size as f32; size];
+        Kernel::separable(weights.clone(), weights)
+    }
+
+    /// Parse a kernel written as rows of weights, either as JSON, e.g. `[[0, -1, 0], [-1, 5, -1],
+    /// [0, -1, 0]]`, or as text with rows on separate lines or separated by `;` and weights
+    /// separated by spaces or commas, e.g. `0 -1 0; -1 5 -1; 0 -1 0`. Lines starting with `#` are
+    /// comments. Both sides need to be odd so the kernel has a centre pixel.
+    pub fn parse(text: &str) -> Result<Kernel, String> {
+        let rows: Vec<Vec<f32>> = if text.trim_start().starts_with('[') {
+            serde_json::from_str(text).map_err(|e| format!("Invalid JSON kernel: {e}"))?
+        } else {
+            text.lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .flat_map(|line| line.split(';'))
+                .filter(|row| !row.trim().is_empty())
+                .map(|row| {
+                    row.split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|weight| !weight.is_empty())
+                        .map(|weight| weight.parse::<f32>().map_err(|_| format!("Invalid kernel weight {weight}")))
+                        .collect()
+                })
+                .collect::<Result<_, _>>()?
+        };
+
+        let height = rows.len();
+        let width = rows.first().map_or(0, Vec::len);
+        if width == 0 {
+            return Err(String::from("Kernel is empty"));
+        }
+        if rows.iter().any(|row| row.len() != width) {
+            return Err(String::from("Kernel rows differ in length"));
+        }
+        if width.is_multiple_of(2) || height.is_multiple_of(2) {
+            return Err(format!("Kernel is {width}x{height}, but both sides need to be odd so it has a centre"));
+        }
+
+        Ok(Kernel::from_rows(width, height, rows.concat()))
+    }
+
+    /// Scale the weights to sum to 1, so the kernel keeps the overall brightness. Kernels that sum
+    /// to 0, like edge detectors, have their positive weights scaled to sum to 1 instead.
+    pub fn normalized(&self) -> Kernel {
+        let sum: f32 = self.weights.values().sum();
+        let scale = if sum.abs() > 1e-6 {
+            sum
+        } else {
+            self.weights.values().filter(|weight| **weight > 0.0).sum()
+        };
+
+        if scale == 0.0 {
+            return self.clone();
+        }
+
+        let mut weights = self.weights.clone();
+        weights.map_in_place(|weight| weight / scale);
+        Kernel::new(weights)
+    }
+
+    pub fn width(&self) -> usize {
+        self.weights.width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.weights.height()
+    }
+}
+
+/// Split a kernel into a horizontal and a vertical 1D kernel if it is their outer product, i.e.
+/// every row is a multiple of the row through the largest weight.
+fn factorize(weights: &ImageF32) -> Option<(Vec<f32>, Vec<f32>)> {
+    let (mut pivot_x, mut pivot_y, mut pivot) = (0, 0, 0.0f32);
+    for y in 0..weights.height() {
+        for (x, weight) in weights.row(y).iter().enumerate() {
+            if weight.abs() > pivot.abs() {
+                (pivot_x, pivot_y, pivot) = (x, y, *weight);
+            }
+        }
+    }
+
+    if pivot == 0.0 {
+        return None;
+    }
+
+    let horizontal: Vec<f32> = weights.row(pivot_y).iter().map(|weight| weight / pivot).collect();
+    let vertical: Vec<f32> = (0..weights.height()).map(|y| weights[(pivot_x, y)]).collect();
+
+    let tolerance = pivot.abs() * 1e-5;
+    for (y, vertical_weight) in vertical.iter().enumerate() {
+        for (x, horizontal_weight) in horizontal.iter().enumerate() {
+            if (weights[(x, y)] - horizontal_weight * vertical_weight).abs() > tolerance {
+                return None;
+            }
+        }
+    }
+
+    Some((horizontal, vertical))
+}
+
+// -------------------------------------------------------------------------------------------------
+// Borders
+// -------------------------------------------------------------------------------------------------
+
+/// What the kernel sees past the edge of the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Border {
+    /// Black
+    Zero,
+    /// The nearest edge pixel, repeated
+    Replicate,
+    /// The image mirrored about its edge pixel, which isn't repeated
+    Reflect,
+    /// The opposite side of the image, as if it tiled the plane
+    Wrap,
+}
+
+impl Border {
+    pub fn parse(name: &str) -> Border {
+        match name {
+            "zero" => Border::Zero,
+            "replicate" => Border::Replicate,
+            "reflect" => Border::Reflect,
+            "wrap" => Border::Wrap,
+            _ => panic!("Unknown border mode {name}. Expected one of: zero, replicate, reflect, wrap")
+        }
+    }
+
+    /// Index inside `0..length` that `index` reads from, or `None` for zero.
+    fn source(&self, index: isize, length: usize) -> Option<usize> {
+        let length = length as isize;
+        if (0..length).contains(&index) {
+            return Some(index as usize);
+        }
+
+        match self {
+            Border::Zero => None,
+            Border::Replicate => Some(index.clamp(0, length - 1) as usize),
+            Border::Wrap => Some(index.rem_euclid(length) as usize),
+            Border::Reflect => {
+                if length == 1 {
+                    return Some(0);
+                }
+                let period = 2 * (length - 1);
+                let index = index.rem_euclid(period);
+                Some(if index < length { index } else { period - index } as usize)
+            }
+        }
+    }
+}
+
+/// Copy of the image with extra pixels on each side filled in by `border`.
+pub fn pad(image: &ImageF32, left: usize, right: usize, top: usize, bottom: usize, border: Border) -> ImageF32 {
+    let columns: Vec<Option<usize>> = (0..image.width() + left + right)
+        .map(|x| border.source(x as isize - left as isize, image.width()))
+        .collect();
+
+    let mut padded = ImageF32::new(image.width() + left + right, image.height() + top + bottom);
+    padded.par_rows_mut().for_each(|(y, row)| {
+        let Some(source_y) = border.source(y as isize - top as isize, image.height()) else {
+            return;
+        };
+
+        let source_row = image.row(source_y);
+        for (value, source_x) in row.iter_mut().zip(columns.iter()) {
+            if let Some(source_x) = source_x {
+                *value = source_row[*source_x];
+            }
+        }
+    });
+
+    padded
+}
+
+/// Pad enough for the kernel to be centred on every pixel.
+fn pad_for(image: &ImageF32, kernel: &Kernel, border: Border) -> ImageF32 {
+    let left = kernel.width() / 2;
+    let top = kernel.height() / 2;
+    pad(image, left, kernel.width() - 1 - left, top, kernel.height() - 1 - top, border)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Convolution
+// -------------------------------------------------------------------------------------------------
+
+/// Apply a kernel centred on every pixel, reading past the edges according to `border`, so the
+/// output is the same size as the input. Separable kernels are applied as two 1D passes and large
+/// kernels through an FFT.
+pub fn convolve(image: &ImageF32, kernel: &Kernel, border: Border) -> ImageF32 {
+    let padded = pad_for(image, kernel, border);
+
+    match &kernel.factors {
+        Some((horizontal, vertical)) => {
+            let rows = horizontal_pass(&padded.as_view(), horizontal);
+            vertical_pass(&rows.as_view(), vertical)
+        },
+        None if kernel.width() * kernel.height() >= FFT_MIN_AREA => fft(&padded, &kernel.weights),
+        None => direct(&padded.as_view(), &kernel.weights),
+    }
+}
+
+/// Slide the kernel over every position where it fits entirely inside the image, so the output is
+/// smaller than the input by the kernel size less one.
+pub fn direct(base: &ImageView, kernel: &ImageF32) -> ImageF32 {
+    let width = base.width() + 1 - kernel.width();
+    let height = base.height() + 1 - kernel.height();
+    let mut output = ImageF32::new(width, height);
+
+    // Rows are independent and each is summed in the same order whichever thread it's on, so
+    // the result doesn't depend on the number of threads
+    output.par_rows_mut().for_each(|(y, output_row)| {
+        for kernel_y in 0..kernel.height() {
+            let base_row = base.row(y + kernel_y);
+
+            for (kernel_x, kernel_value) in kernel.row(kernel_y).iter().enumerate() {
+                if *kernel_value == 0.0 {
+                    continue;
+                }
+
+                simd::multiply_add(output_row, &base_row[kernel_x..], *kernel_value);
+            }
+        }
+    });
+
+    output
+}
+
+/// Apply a 1D kernel along every row, shrinking the width by its length less one.
+fn horizontal_pass(base: &ImageView, kernel: &[f32]) -> ImageF32 {
+    direct(base, &ImageF32::from_vec(kernel.len(), 1, kernel.to_vec()))
+}
+
+/// Apply a 1D kernel down every column, shrinking the height by its length less one.
+fn vertical_pass(base: &ImageView, kernel: &[f32]) -> ImageF32 {
+    direct(base, &ImageF32::from_vec(1, kernel.len(), kernel.to_vec()))
+}
+
+/// Transform every row, then every column, of a complex buffer in place.
+fn fft_2d(buffer: &mut [Complex<f32>], width: usize, height: usize, inverse: bool) {
+    let mut planner = FftPlanner::new();
+    let (row_fft, column_fft) = if inverse {
+        (planner.plan_fft_inverse(width), planner.plan_fft_inverse(height))
+    } else {
+        (planner.plan_fft_forward(width), planner.plan_fft_forward(height))
+    };
+
+    buffer.par_chunks_mut(width).for_each(|row| row_fft.process(row));
+
+    // Transpose so the columns are contiguous and can be transformed in parallel too
+    let mut columns = vec![Complex::new(0.0, 0.0); width * height];
+    columns.par_chunks_mut(height).enumerate().for_each(|(x, column)| {
+        for (y, value) in column.iter_mut().enumerate() {
+            *value = buffer[y * width + x];
+        }
+        column_fft.process(column);
+    });
+
+    buffer.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = columns[x * height + y];
+        }
+    });
+}
+
+/// The same as `direct`, through the FFT of the padded image. Correlating with the kernel is
+/// multiplying by the conjugate of its transform. The transform is circular, but none of the
+/// positions where the kernel fits inside the image wrap around.
+fn fft(base: &ImageF32, kernel: &ImageF32) -> ImageF32 {
+    let (width, height) = (base.width(), base.height());
+
+    let mut image_buffer: Vec<Complex<f32>> = base.values().map(|value| Complex::new(*value, 0.0)).collect();
+    let mut kernel_buffer = vec![Complex::new(0.0, 0.0); width * height];
+    for y in 0..kernel.height() {
+        for (x, weight) in kernel.row(y).iter().enumerate() {
+            kernel_buffer[y * width + x] = Complex::new(*weight, 0.0);
+        }
+    }
+
+    fft_2d(&mut image_buffer, width, height, false);
+    fft_2d(&mut kernel_buffer, width, height, false);
+
+    for (value, kernel_value) in image_buffer.iter_mut().zip(kernel_buffer.iter()) {
+        *value *= kernel_value.conj();
+    }
+
+    fft_2d(&mut image_buffer, width, height, true);
+
+    // rustfft doesn't normalize the inverse
+    let scale = 1.0 / (width * height) as f32;
+    let output_width = width + 1 - kernel.width();
+    let output_height = height + 1 - kernel.height();
+
+    ImageF32::from_fn(output_width, output_height, |x, y| image_buffer[y * width + x].re * scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(width: usize, height: usize) -> ImageF32 {
+        ImageF32::from_fn(width, height, |x, y| ((x * 7919 + y * 104729) % 251) as f32)
+    }
+
+    fn assert_close(a: &ImageF32, b: &ImageF32, tolerance: f32) {
+        assert_eq!((a.width(), a.height()), (b.width(), b.height()));
+        for (a, b) in a.values().zip(b.values()) {
+            assert!((a - b).abs() <= tolerance, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn separable_direct_and_fft_agree() {
+        let image = noise(40, 30);
+        let kernel = Kernel::gaussian(2.0, 7);
+        let padded = pad_for(&image, &kernel, Border::Reflect);
+
+        let separable = convolve(&image, &kernel, Border::Reflect);
+        let direct = direct(&padded.as_view(), &kernel.weights);
+        let fft = fft(&padded, &kernel.weights);
+
+        assert_close(&separable, &direct, 1e-3);
+        assert_close(&fft, &direct, 1e-2);
+    }
+
+    #[test]
+    fn kernels_are_applied_without_flipping() {
+        let ramp = ImageF32::from_fn(8, 8, |x, _| x as f32);
+        let gradient = convolve(&ramp, &Kernel::from_rows(3, 1, vec![-1.0, 0.0, 1.0]), Border::Replicate);
+
+        assert_eq!(gradient[(4, 4)], 2.0);
+    }
+
+    #[test]
+    fn borders_read_from_the_right_pixels() {
+        let sources = |border: Border| -> Vec<Option<usize>> { (-3..7).map(|index| border.source(index, 4)).collect() };
+
+        assert_eq!(sources(Border::Zero), vec![None, None, None, Some(0), Some(1), Some(2), Some(3), None, None, None]);
+        assert_eq!(sources(Border::Replicate), vec![Some(0), Some(0), Some(0), Some(0), Some(1), Some(2), Some(3), Some(3), Some(3), Some(3)]);
+        assert_eq!(sources(Border::Reflect), vec![Some(3), Some(2), Some(1), Some(0), Some(1), Some(2), Some(3), Some(2), Some(1), Some(0)]);
+        assert_eq!(sources(Border::Wrap), vec![Some(1), Some(2), Some(3), Some(0), Some(1), Some(2), Some(3), Some(0), Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn parse_reads_json_and_text() {
+        let json = Kernel::parse("[[0, -1, 0], [-1, 5, -1], [0, -1, 0]]").unwrap();
+        let text = Kernel::parse("# sharpen\n0 -1 0; -1,5,-1\n0 -1 0").unwrap();
+
+        assert_eq!(json.weights.values().collect::<Vec<_>>(), text.weights.values().collect::<Vec<_>>());
+        assert!(Kernel::parse("1 1; 1 1").is_err());
+        assert!(Kernel::parse("1 1 1; 1 1").is_err());
+        assert!(Kernel::parse("1 x 1").is_err());
+    }
+
+    #[test]
+    fn only_outer_products_are_factorized() {
+        assert!(Kernel::parse("-1 0 1; -2 0 2; -1 0 1").unwrap().factors.is_some());
+        assert!(Kernel::parse("0 1 0; 1 -4 1; 0 1 0").unwrap().factors.is_none());
+    }
+
+    #[test]
+    fn normalized_keeps_brightness() {
+        let blur = Kernel::parse("1 2 1; 2 4 2; 1 2 1").unwrap().normalized();
+        let edges = Kernel::parse("0 -1 0; -1 4 -1; 0 -1 0").unwrap().normalized();
+
+        assert!((blur.weights.values().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!((edges.weights.values().filter(|weight| **weight > 0.0).sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+}
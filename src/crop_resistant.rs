@@ -0,0 +1,232 @@
+use std::fmt;
+
+use image::{DynamicImage, GenericImageView, GrayImage};
+use image::imageops::{self, FilterType};
+
+use crate::phash::{Hash, ImageHasher};
+
+/// Images are halved until their longer side is at most this before segmenting. Halving keeps
+/// the aspect ratio, and a crop of an image is shrunk by the same factor as the original unless
+/// it crosses a power of two, so the same regions are found in both.
+const SEGMENTATION_SIZE: u32 = 512;
+
+/// Regions smaller than this many pixels of the segmentation image are too small to be stable.
+const MIN_SEGMENT_SIZE: usize = 200;
+
+/// Brightness levels separating the bands that regions are made of
+const SEGMENT_THRESHOLDS: [u8; 3] = [64, 128, 192];
+
+/// Only the largest regions are hashed
+const MAX_SEGMENTS: usize = 32;
+
+/// A set of hashes, one for each region of an image. Cropping an image only loses the regions
+/// that were cut, so the rest still match.
+#[derive(Debug, Clone)]
+pub struct MultiHash {
+    pub hashes: Vec<Hash>,
+}
+
+impl MultiHash {
+    /// Parse a comma separated list of hashes, as printed by `Display`.
+    pub fn parse(text: &str) -> Option<MultiHash> {
+        let hashes: Option<Vec<Hash>> = text.split(',').map(Hash::parse).collect();
+        Some(MultiHash { hashes: hashes? })
+    }
+
+    /// Number of our regions that have a match in the other image within `threshold` bits.
+    pub fn matching_regions(&self, other: &MultiHash, threshold: u32) -> usize {
+        self.hashes.iter()
+            .filter(|hash| other.hashes.iter().any(|other_hash| hash.distance(other_hash).is_some_and(|distance| distance <= threshold)))
+            .count()
+    }
+}
+
+impl fmt::Display for MultiHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hashes: Vec<String> = self.hashes.iter().map(|hash| hash.to_hex()).collect();
+        write!(f, "{}", hashes.join(","))
+    }
+}
+
+/// A connected region of the segmentation image.
+struct Segment {
+    size: usize,
+    sum_x: u64,
+    sum_y: u64,
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
+}
+
+impl Segment {
+    fn centroid(&self) -> (f32, f32) {
+        (self.sum_x as f32 / self.size as f32, self.sum_y as f32 / self.size as f32)
+    }
+
+    /// Whether the region reaches the edge of the image, where a crop could cut it.
+    fn touches_border(&self, width: u32, height: u32) -> bool {
+        self.min_x == 0 || self.min_y == 0 || self.max_x + 1 == width || self.max_y + 1 == height
+    }
+}
+
+/// Which band of `SEGMENT_THRESHOLDS` a brightness falls in.
+fn band(value: u8) -> usize {
+    SEGMENT_THRESHOLDS.iter().filter(|threshold| value > **threshold).count()
+}
+
+/// Split a grayscale image into connected regions of pixels in the same brightness band. Fixed
+/// thresholds are used since thresholds taken from the image itself, like the median, would
+/// move when the image is cropped. Regions touching the edge of the image are left out, since
+/// cropping would change their shape.
+fn segment(gray: &GrayImage) -> Vec<Segment> {
+    let (width, height) = gray.dimensions();
+    let bands: Vec<usize> = gray.pixels().map(|pixel| band(pixel.0[0])).collect();
+    let mut visited = vec![false; bands.len()];
+    let mut segments = Vec::new();
+
+    for start in 0..bands.len() {
+        if visited[start] {
+            continue;
+        }
+
+        let class = bands[start];
+        let mut segment = Segment { size: 0, sum_x: 0, sum_y: 0, min_x: u32::MAX, min_y: u32::MAX, max_x: 0, max_y: 0 };
+        let mut stack = vec![start];
+        visited[start] = true;
+
+        // Flood fill with 4-connectivity
+        while let Some(index) = stack.pop() {
+            let x = index as u32 % width;
+            let y = index as u32 / width;
+
+            segment.size += 1;
+            segment.sum_x += x as u64;
+            segment.sum_y += y as u64;
+            segment.min_x = segment.min_x.min(x);
+            segment.min_y = segment.min_y.min(y);
+            segment.max_x = segment.max_x.max(x);
+            segment.max_y = segment.max_y.max(y);
+
+            let mut neighbours = Vec::with_capacity(4);
+            if x > 0 { neighbours.push(index - 1); }
+            if x + 1 < width { neighbours.push(index + 1); }
+            if y > 0 { neighbours.push(index - width as usize); }
+            if y + 1 < height { neighbours.push(index + width as usize); }
+
+            for neighbour in neighbours {
+                if !visited[neighbour] && bands[neighbour] == class {
+                    visited[neighbour] = true;
+                    stack.push(neighbour);
+                }
+            }
+        }
+
+        if segment.size >= MIN_SEGMENT_SIZE && !segment.touches_border(width, height) {
+            segments.push(segment);
+        }
+    }
+
+    segments.sort_by_key(|segment| std::cmp::Reverse(segment.size));
+    segments.truncate(MAX_SEGMENTS);
+    segments
+}
+
+/// Segment the image and hash a square patch around the centre of every large enough region
+/// with `hasher`. The patch is as wide as the region's larger side, in pixels of the original
+/// image, so it covers the same content in a crop.
+pub fn hash(image: &DynamicImage, hasher: &dyn ImageHasher) -> MultiHash {
+    let (width, height) = image.dimensions();
+
+    let mut scale = 1;
+    while width.max(height) / scale > SEGMENTATION_SIZE {
+        scale *= 2;
+    }
+
+    let gray = image.resize_exact(width / scale, height / scale, FilterType::Triangle).into_luma8();
+    let blurred = imageops::blur(&gray, 1.0);
+
+    let hashes = segment(&blurred).iter().filter_map(|segment| {
+        let (centre_x, centre_y) = segment.centroid();
+        let side = (segment.max_x - segment.min_x).max(segment.max_y - segment.min_y) + 1;
+        let half = (side * scale) as f32 / 2.0;
+
+        let x = (centre_x + 0.5) * scale as f32 - half;
+        let y = (centre_y + 0.5) * scale as f32 - half;
+        let side = (2.0 * half) as u32;
+
+        // Skip patches that would hang off the image, or slivers that would hash to noise
+        if x < 0.0 || y < 0.0 || x as u32 + side > width || y as u32 + side > height || side < 8 {
+            return None;
+        }
+
+        Some(hasher.hash(&image.crop_imm(x as u32, y as u32, side, side)))
+    }).collect();
+
+    MultiHash { hashes }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_hash_round_trips_odd_lengths() {
+        let hashes = vec![
+            Hash::from_bits(&[true, false, true, true, false]),
+            Hash::from_bits(&[false; 25]),
+            Hash::from_bits(&[true; 64]),
+        ];
+        let multi_hash = MultiHash { hashes: hashes.clone() };
+
+        assert_eq!(MultiHash::parse(&multi_hash.to_string()).unwrap().hashes, hashes);
+    }
+
+    /// Discs of different brightness scattered over a mid grey background, each striped at its
+    /// own angle and frequency so their patches don't all hash the same.
+    fn discs(seed: u64) -> DynamicImage {
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let discs: Vec<(f32, f32, f32, f32, f32, f32)> = (0..24)
+            .map(|_| (
+                rng.gen_range(20.0..380.0),
+                rng.gen_range(20.0..280.0),
+                rng.gen_range(8.0..24.0),
+                rng.gen_range(0..4) as f32 * 64.0 + 20.0,
+                rng.gen_range(0.0..std::f32::consts::PI),
+                rng.gen_range(0.2..0.8),
+            ))
+            .collect();
+
+        DynamicImage::ImageLuma8(GrayImage::from_fn(400, 300, |x, y| {
+            let (x, y) = (x as f32, y as f32);
+            let value = discs.iter().rev()
+                .find(|(cx, cy, r, ..)| (x - cx).powi(2) + (y - cy).powi(2) < r * r)
+                .map_or(140.0, |(_, _, _, level, angle, frequency)| level + 20.0 * (frequency * (x * angle.cos() + y * angle.sin())).sin());
+            image::Luma([value.clamp(0.0, 255.0) as u8])
+        }))
+    }
+
+    #[test]
+    fn cropped_copies_match() {
+        let hasher = crate::phash::hasher("phash", 8).unwrap();
+        let original = discs(1);
+        let hash_original = hash(&original, hasher.as_ref());
+
+        for (x, y, width, height) in [(20, 15, 360, 270), (0, 0, 300, 225), (100, 30, 300, 225)] {
+            let cropped = hash(&original.crop_imm(x, y, width, height), hasher.as_ref());
+            assert!(cropped.matching_regions(&hash_original, 10) >= 2, "crop at {x},{y} of {width}x{height}");
+        }
+    }
+
+    #[test]
+    fn unrelated_images_dont_match() {
+        let hasher = crate::phash::hasher("phash", 8).unwrap();
+        let first = hash(&discs(1), hasher.as_ref());
+        let second = hash(&discs(2), hasher.as_ref());
+
+        assert!(!first.hashes.is_empty());
+        assert!(first.matching_regions(&second, 10) < 2);
+    }
+}
@@ -24,6 +24,14 @@ fn get_random( dest: &mut [u8]) {
     sr.fill( dest ).unwrap();
 }
 
+/// Derive a deterministic 32 byte seed from a password, for keying RNGs
+pub fn seed( password: &str ) -> [u8; 32] {
+    let hash = ring::digest::digest( &ring::digest::SHA256, password.as_bytes() );
+    let mut seed = [0u8; 32];
+    seed.copy_from_slice( hash.as_ref() );
+    return seed;
+}
+
 fn nonce() -> Vec<u8> {
     let mut randoms: [u8; 24] = [0; 24];
     get_random( &mut randoms );
@@ -65,13 +73,22 @@ pub fn encrypt( password: String, data: String ) -> Vec<u8> {
 }
 
 pub fn decrypt( password: String, cipher_text: &[u8] ) -> Vec<u8> {
+    return try_decrypt( password, cipher_text ).expect( "Failed to decrypt data" );
+}
+
+/// Decrypt, returning `None` if the cipher text is malformed or the password is wrong
+pub fn try_decrypt( password: String, cipher_text: &[u8] ) -> Option<Vec<u8>> {
+    if cipher_text.len() < NONCE_PLUS_AD_SIZE + POLY1305_OUTSIZE {
+        return None;
+    }
+
     let key = create_key(password, cipher_text[..XCHACHA_NONCESIZE].to_vec());
     let split = split_encrypted( cipher_text );
-    let nonce = Nonce::from_slice( split.0.as_slice() ).unwrap();
+    let nonce = Nonce::from_slice( split.0.as_slice() ).ok()?;
     let mut output = vec![0u8; split.2.len()];
 
-    open(&key, &nonce, split.2.as_slice(), Some( split.1.as_slice() ), &mut output ).unwrap();
+    open(&key, &nonce, split.2.as_slice(), Some( split.1.as_slice() ), &mut output ).ok()?;
     // Remove any remaining padding
     output.retain(|&x| x != 0u8);
-    return output.to_vec();
+    return Some( output.to_vec() );
 }
\ No newline at end of file
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use rustfft::{Fft, FftPlanner};
+use rustfft::num_complex::Complex;
+
+/// Type-II discrete cosine transform, computed through an FFT of the input mirrored to twice its
+/// length. Coefficients are unnormalized: `X[k] = sum(x[n] * cos(PI * (n + 0.5) * k / N))`.
+pub struct Dct {
+    size: usize,
+    fft: Arc<dyn Fft<f32>>,
+    twiddles: Vec<Complex<f32>>,
+}
+
+impl Dct {
+    pub fn new(size: usize) -> Dct {
+        let fft = FftPlanner::new().plan_fft_forward(2 * size);
+        let twiddles = (0..size)
+            .map(|k| Complex::from_polar(0.5, -std::f32::consts::PI * k as f32 / (2 * size) as f32))
+            .collect();
+
+        Dct { size, fft, twiddles }
+    }
+
+    pub fn process(&self, input: &[f32]) -> Vec<f32> {
+        let mut buffer: Vec<Complex<f32>> = input.iter()
+            .chain(input.iter().rev())
+            .map(|value| Complex::new(*value, 0.0))
+            .collect();
+
+        self.fft.process(&mut buffer);
+
+        (0..self.size).map(|k| (buffer[k] * self.twiddles[k]).re).collect()
+    }
+
+    /// Transform a square block, rows then columns. Indexed `[x][y]` like the rest of the crate.
+    pub fn process_2d(&self, block: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let rows: Vec<Vec<f32>> = block.iter().map(|row| self.process(row)).collect();
+        let mut output = vec![vec![0.0; self.size]; self.size];
+
+        for y in 0..self.size {
+            let column: Vec<f32> = rows.iter().map(|row| row[y]).collect();
+            for (x, value) in self.process(&column).into_iter().enumerate() {
+                output[x][y] = value;
+            }
+        }
+
+        output
+    }
+}
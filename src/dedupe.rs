@@ -0,0 +1,266 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::bktree::BkTree;
+use crate::phash::{Hash, HashConfig};
+
+/// Extensions of files worth hashing.
+const IMAGE_EXTENSIONS: [&str; 9] = ["png", "jpg", "jpeg", "gif", "bmp", "tif", "tiff", "webp", "ico"];
+
+#[derive(Debug, Serialize)]
+pub struct File {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// A group of near-duplicate files, largest first.
+#[derive(Debug, Serialize)]
+pub struct Cluster {
+    pub files: Vec<File>,
+}
+
+fn is_image(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => IMAGE_EXTENSIONS.contains(&extension.to_lowercase().as_str()),
+        None => false
+    }
+}
+
+/// Every image file under a directory, recursively. Symbolic links are skipped, so a link back
+/// up the tree can't loop and a file reached through several links isn't reported twice.
+pub fn walk(directory: &Path) -> Vec<PathBuf> {
+    let mut images = Vec::new();
+    let mut directories = vec![directory.to_path_buf()];
+
+    while let Some(current) = directories.pop() {
+        let entries = match fs::read_dir(&current) {
+            Ok(entries) => entries,
+            Err(e) => {
+                println!("Skipping {}: {e}", current.display());
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => {
+                    println!("Skipping {}: {e}", path.display());
+                    continue;
+                }
+            };
+
+            if file_type.is_dir() {
+                directories.push(path);
+            } else if file_type.is_file() && is_image(&path) {
+                images.push(path);
+            }
+        }
+    }
+
+    images.sort();
+    images
+}
+
+/// Hash a single file, or `None` if it can't be opened as an image.
+pub fn hash_file(path: &Path, config: &HashConfig) -> Option<Hash> {
+    match image::open(path) {
+        Ok(image) => Some(config.hasher().hash(&image)),
+        Err(e) => {
+            println!("Skipping {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Hash every file on the thread pool. Files that fail to open are left out.
+pub fn hash_files(paths: &[PathBuf], config: &HashConfig) -> Vec<(PathBuf, Hash)> {
+    paths.par_iter()
+        .filter_map(|path| hash_file(path, config).map(|hash| (path.clone(), hash)))
+        .collect()
+}
+
+/// Group hashes around the largest file: each cluster holds a file and every not yet clustered
+/// file within `threshold` of it. Files are never grouped only through a chain of other files,
+/// so everything after the first file of a cluster is a near duplicate of that first file.
+/// Only groups of two or more are returned.
+pub fn cluster(hashes: Vec<(PathBuf, Hash)>, threshold: u32) -> Vec<Cluster> {
+    let mut files: Vec<(File, Hash)> = hashes.into_iter()
+        .map(|(path, hash)| {
+            let size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+            (File { path, size }, hash)
+        })
+        .collect();
+    files.sort_by(|(a, _), (b, _)| b.size.cmp(&a.size).then(a.path.cmp(&b.path)));
+
+    let mut tree = BkTree::new();
+    for (index, (_, hash)) in files.iter().enumerate() {
+        tree.insert(hash.clone(), index);
+    }
+
+    // Walking the files largest first makes each representative the file that gets kept
+    let mut group_of: Vec<Option<usize>> = vec![None; files.len()];
+    for index in 0..files.len() {
+        if group_of[index].is_some() {
+            continue;
+        }
+
+        for (_, other) in tree.find(&files[index].1, threshold) {
+            if group_of[*other].is_none() {
+                group_of[*other] = Some(index);
+            }
+        }
+    }
+
+    let mut groups: Vec<Vec<File>> = (0..files.len()).map(|_| Vec::new()).collect();
+    for ((file, _), group) in files.into_iter().zip(group_of) {
+        groups[group.expect("Every file is at least in its own group")].push(file);
+    }
+
+    // Files were added in size order, so the representative is already first
+    let mut clusters: Vec<Cluster> = groups.into_iter()
+        .filter(|files| files.len() > 1)
+        .map(|files| Cluster { files })
+        .collect();
+
+    clusters.sort_by(|a, b| a.files[0].path.cmp(&b.files[0].path));
+    clusters
+}
+
+pub fn format_text(clusters: &[Cluster]) -> String {
+    let mut lines = Vec::new();
+
+    for (i, cluster) in clusters.iter().enumerate() {
+        lines.push(format!("Cluster {}:", i + 1));
+        for file in cluster.files.iter() {
+            lines.push(format!("  {} ({} bytes)", file.path.display(), file.size));
+        }
+    }
+
+    lines.join("\n")
+}
+
+pub fn format_json(clusters: &[Cluster]) -> String {
+    serde_json::to_string_pretty(clusters).expect("Failed to serialize clusters")
+}
+
+/// Delete every file in each cluster except the largest.
+pub fn delete_duplicates(clusters: &[Cluster]) {
+    for file in clusters.iter().flat_map(|cluster| cluster.files.iter().skip(1)) {
+        println!("Deleting {}", file.path.display());
+        if let Err(e) = fs::remove_file(&file.path) {
+            println!("Failed to delete {}: {e}", file.path.display());
+        }
+    }
+}
+
+/// Move every file in each cluster except the largest into `destination`.
+pub fn move_duplicates(clusters: &[Cluster], destination: &Path) {
+    fs::create_dir_all(destination).expect("Failed to create destination directory");
+
+    for file in clusters.iter().flat_map(|cluster| cluster.files.iter().skip(1)) {
+        let mut target = destination.join(file.path.file_name().expect("File has no name"));
+
+        // Don't overwrite duplicates from different directories that share a name
+        let mut suffix = 1;
+        while target.exists() {
+            let name = format!("{}_{}", suffix, file.path.file_name().unwrap().to_string_lossy());
+            target = destination.join(name);
+            suffix += 1;
+        }
+
+        println!("Moving {} to {}", file.path.display(), target.display());
+        if let Err(e) = fs::rename(&file.path, &target) {
+            println!("Failed to move {}: {e}", file.path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 64 bit hash with the first `ones` bits set.
+    fn hash(ones: usize) -> Hash {
+        let bits: Vec<bool> = (0..64).map(|i| i < ones).collect();
+        Hash::from_bits(&bits)
+    }
+
+    fn paths(cluster: &Cluster) -> Vec<&str> {
+        cluster.files.iter().map(|file| file.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn cluster_groups_near_duplicates_of_the_largest_file() {
+        // d is close to the largest file a, e and f are close to each other but not to a
+        let hashes = vec![
+            (PathBuf::from("a.png"), hash(0)),
+            (PathBuf::from("d.png"), hash(3)),
+            (PathBuf::from("e.png"), hash(30)),
+            (PathBuf::from("f.png"), hash(32)),
+            (PathBuf::from("g.png"), hash(60)),
+        ];
+
+        let clusters = cluster(hashes, 5);
+
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(paths(&clusters[0]), vec!["a.png", "d.png"]);
+        assert_eq!(paths(&clusters[1]), vec!["e.png", "f.png"]);
+    }
+
+    #[test]
+    fn chained_files_far_from_the_kept_file_are_not_deleted() {
+        // a-b and b-c are within the threshold but a-c isn't, so deleting b must leave c alone
+        let root = std::env::temp_dir().join(format!("acetic_chain_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let hashes: Vec<(PathBuf, Hash)> = [("a.png", 3, 0), ("b.png", 2, 4), ("c.png", 1, 8)].into_iter()
+            .map(|(name, size, ones)| {
+                let path = root.join(name);
+                fs::write(&path, vec![0; size]).unwrap();
+                (path, hash(ones))
+            })
+            .collect();
+
+        let clusters = cluster(hashes, 5);
+        delete_duplicates(&clusters);
+        let remaining: Vec<bool> = ["a.png", "b.png", "c.png"].iter().map(|name| root.join(name).exists()).collect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].files.len(), 2);
+        assert_eq!(remaining, vec![true, false, true]);
+    }
+
+    #[test]
+    fn walk_finds_images_in_subdirectories() {
+        let root = std::env::temp_dir().join(format!("acetic_walk_{}", std::process::id()));
+        fs::create_dir_all(root.join("nested")).unwrap();
+        for name in ["a.png", "b.JPG", "notes.txt", "nested/c.gif"] {
+            fs::write(root.join(name), b"").unwrap();
+        }
+
+        let found: Vec<PathBuf> = walk(&root).into_iter().map(|path| path.strip_prefix(&root).unwrap().to_path_buf()).collect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(found, vec![PathBuf::from("a.png"), PathBuf::from("b.JPG"), PathBuf::from("nested/c.gif")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_skips_symbolic_links() {
+        let root = std::env::temp_dir().join(format!("acetic_walk_links_{}", std::process::id()));
+        fs::create_dir_all(root.join("nested")).unwrap();
+        fs::write(root.join("nested/a.png"), b"").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("nested/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("nested/a.png"), root.join("link.png")).unwrap();
+
+        let found: Vec<PathBuf> = walk(&root).into_iter().map(|path| path.strip_prefix(&root).unwrap().to_path_buf()).collect();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(found, vec![PathBuf::from("nested/a.png")]);
+    }
+}
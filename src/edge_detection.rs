@@ -1,296 +1,688 @@
+use std::f32::consts::{FRAC_PI_4, PI};
+
+use image::{DynamicImage, Rgb, RgbImage};
+use rayon::prelude::*;
+
+use crate::convolution::{self, Border, Kernel};
+use crate::image_f32::ImageF32;
+use crate::simd;
+
 // -------------------------------------------------------------------------------------------------
-// Convolution
+// Helper functions
 // -------------------------------------------------------------------------------------------------
 
-/// Take an image in the form of a Vector of rows and cols including the RGB values of each pixel
-fn convolve(base: &Vec<Vec<f32>>, kernel: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
-    let mut final_vec = base.clone();
+/// Combine two images into a new one, with `f` filling each output row from the same row of
+/// both.
+fn combine( one: &ImageF32, two: &ImageF32, f: impl Fn( &mut [f32], &[f32], &[f32] ) + Sync ) -> ImageF32 {
+    assert_eq!( ( one.width(), one.height() ), ( two.width(), two.height() ), "Images differ in size" );
 
-    for x in 0..(base.len() - kernel.len()) {
-        for y in 0..(base[x as usize].len() - kernel[0].len()) {
-            let mut val: f32 = 0.0;
+    let mut output = ImageF32::new( one.width(), one.height() );
+    output.par_rows_mut().for_each( |( y, row )| f( row, one.row( y ), two.row( y ) ) );
+    return output;
+}
 
-            for kernel_x in 0..kernel.len() {
-                for kernel_y in 0..kernel[kernel_x as usize].len() {
-                    let kernel_value = kernel[kernel_x as usize][kernel_y as usize];
+/// Pure colour at `hue` degrees around the colour wheel, scaled by `value` from 0 to 1.
+fn hue_to_rgb( hue: f32, value: f32 ) -> Rgb<u8> {
+    let sector = hue.rem_euclid( 360.0 ) / 60.0;
+    let rising = sector.fract();
+    let ( red, green, blue ) = match sector as u32 {
+        0 => ( 1.0, rising, 0.0 ),
+        1 => ( 1.0 - rising, 1.0, 0.0 ),
+        2 => ( 0.0, 1.0, rising ),
+        3 => ( 0.0, 1.0 - rising, 1.0 ),
+        4 => ( rising, 0.0, 1.0 ),
+        _ => ( 1.0, 0.0, 1.0 - rising ),
+    };
 
-                    val += base[(x + kernel_x) as usize][(y + kernel_y) as usize] * kernel_value;
-                }
-            }
-            final_vec[x as usize][y as usize] = val;
-        }
-    }
-    return final_vec;
+    let scale = value.clamp( 0.0, 1.0 ) * 255.0;
+    return Rgb( [( red * scale ) as u8, ( green * scale ) as u8, ( blue * scale ) as u8] );
+}
+
+/// Convert an sRGB colour from 0 to 1 into CIE L*a*b* under D65 light.
+fn srgb_to_lab( rgb: [f32; 3] ) -> [f32; 3] {
+    let [red, green, blue] = rgb.map( |value| {
+        if value <= 0.04045 { value / 12.92 } else { ( ( value + 0.055 ) / 1.055 ).powf( 2.4 ) }
+    } );
+
+    // XYZ relative to the D65 white point
+    let x = ( 0.4124564 * red + 0.3575761 * green + 0.1804375 * blue ) / 0.95047;
+    let y = 0.2126729 * red + 0.7151522 * green + 0.072175 * blue;
+    let z = ( 0.0193339 * red + 0.119192 * green + 0.9503041 * blue ) / 1.08883;
+
+    let f = |t: f32| {
+        let delta: f32 = 6.0 / 29.0;
+        if t > delta.powi( 3 ) { t.cbrt() } else { t / ( 3.0 * delta * delta ) + 4.0 / 29.0 }
+    };
+    let ( fx, fy, fz ) = ( f( x ), f( y ), f( z ) );
+
+    return [116.0 * fy - 16.0, 500.0 * ( fx - fy ), 200.0 * ( fy - fz )];
 }
 
 // -------------------------------------------------------------------------------------------------
-// Helper functions
+// Parameters
 // -------------------------------------------------------------------------------------------------
 
-fn flatten<T>(nested: Vec<Vec<T>>) -> Vec<T> {
-    nested.into_iter().flatten().into_iter().collect()
+/// How the horizontal and vertical gradients are combined into a magnitude.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GradientNorm {
+    /// `|gx| + |gy|`, cheaper and slightly favours diagonal edges
+    L1,
+    /// `sqrt(gx^2 + gy^2)`, the true magnitude
+    L2,
 }
 
-fn add_padding( vec: Vec<Vec<f32>>, padding: i32 ) -> Vec<Vec<f32>> {
-    let mut final_vec: Vec<Vec<f32>> = vec![vec![0.0; vec[0].len() + (padding * 2) as usize]; vec.len() + (padding * 2) as usize];
-    for x in padding as isize..(final_vec.len() - padding as usize) as isize {
-        for y in padding as isize..(final_vec[x as usize].len() - padding as usize) as isize {
-            final_vec[x as usize][y as usize] = vec[(x-padding as isize) as usize][(y-padding as isize) as usize];
-        }
-    }
-
-    // Set padding pixels to the nearest-ish values
-    for x in 0..final_vec.len() {
-        for y in 0..final_vec[0].len() {
-            if x < (padding as usize) {
-                final_vec[x][y] = final_vec[2][y].clone()
-            } else if x > (final_vec.len() - padding as usize) {
-                final_vec[x][y] = final_vec[x-3][y].clone()
-            } else if y < (padding as usize) {
-                final_vec[x][y] = final_vec[x][2].clone()
-            } else if y > (final_vec.len() - padding as usize) {
-                final_vec[x][y] = final_vec[x][y-3].clone()
-            }
+impl GradientNorm {
+    pub fn parse( name: &str ) -> GradientNorm {
+        match name.to_lowercase().as_str() {
+            "l1" => GradientNorm::L1,
+            "l2" => GradientNorm::L2,
+            _ => panic!( "Unknown gradient norm {name}. Expected one of: l1, l2" )
         }
     }
+}
 
-    return final_vec;
+/// Colour space that colour edges are measured in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    /// Red, green and blue as stored
+    Rgb,
+    /// CIE L*a*b*, where colours the same distance apart look about as different as each other
+    Lab,
 }
 
-fn remove_padding( mut vec: Vec<Vec<f32>>, padding: i32 ) -> Vec<Vec<f32>> {
-    let mut final_vec: Vec<Vec<f32>> = vec![vec![0.0; vec[0].len() - (padding * 2) as usize]; vec.len() - (padding * 2) as usize];
-    let start = padding as isize;
-    let stop = (vec.len() - padding as usize) as isize;
-    for x in start..stop {
-        for y in start..(vec[x as usize].len() - padding as usize) as isize {
-            final_vec[(x-padding as isize) as usize][(y-padding as isize) as usize] = vec[x as usize][y as usize];
+impl ColorSpace {
+    pub fn parse( name: &str ) -> ColorSpace {
+        match name.to_lowercase().as_str() {
+            "rgb" => ColorSpace::Rgb,
+            "lab" => ColorSpace::Lab,
+            _ => panic!( "Unknown colour space {name}. Expected one of: rgb, lab" )
         }
     }
-    return final_vec;
 }
 
-fn hypot( vec_one: &Vec<Vec<f32>>, vec_two: &Vec<Vec<f32>> ) -> Vec<Vec<f32>>{
-    let mut final_vec = vec![vec![0.0; vec_one[0].len() as usize]; vec_one.len() as usize];
-    for x in 0..vec_one.len() {
-        for y in 0..vec_one[x].len() {
-            let val_1 = vec_one[x][y].clone();
-            let val_2 = vec_two[x][y].clone();
+/// Which edge detector to run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    /// Blur, Sobel gradients, non-maximum suppression and hysteresis
+    Canny,
+    /// Gradient magnitude from 3x3 kernels weighting the centre row twice as much
+    Sobel,
+    /// Gradient magnitude from 3x3 kernels weighting every row the same
+    Prewitt,
+    /// Gradient magnitude from 3x3 kernels tuned to respond the same in every direction
+    Scharr,
+    /// Gradient magnitude from 2x2 kernels along the two diagonals
+    Roberts,
+    /// Zero crossings of the Laplacian of the blurred image
+    LaplacianOfGaussian,
+    /// Zero crossings of the difference between two blurs, a cheaper stand in for the Laplacian
+    /// of Gaussian
+    DifferenceOfGaussians,
+}
 
-            final_vec[x][y] = val_1.hypot(val_2);
+impl Operator {
+    pub fn parse( name: &str ) -> Operator {
+        match name.to_lowercase().as_str() {
+            "canny" => Operator::Canny,
+            "sobel" => Operator::Sobel,
+            "prewitt" => Operator::Prewitt,
+            "scharr" => Operator::Scharr,
+            "roberts" => Operator::Roberts,
+            "log" => Operator::LaplacianOfGaussian,
+            "dog" => Operator::DifferenceOfGaussians,
+            _ => panic!( "Unknown edge operator {name}. Expected one of: canny, sobel, prewitt, scharr, roberts, log, dog" )
         }
     }
-    return final_vec;
+
+    /// Kernels measuring the gradient along two perpendicular axes, and the angle from the x axis
+    /// to the first of them. `None` for operators that don't measure a gradient.
+    fn gradient_kernels( &self ) -> Option<( Kernel, Kernel, f32 )> {
+        let ( smoothing, rotation ) = match self {
+            Operator::Canny | Operator::Sobel => ( vec![1.0, 2.0, 1.0], 0.0 ),
+            Operator::Prewitt => ( vec![1.0, 1.0, 1.0], 0.0 ),
+            Operator::Scharr => ( vec![3.0, 10.0, 3.0], 0.0 ),
+            Operator::Roberts => {
+                // Differences down and to the right, then down and to the left, i.e. axes turned
+                // by 45 degrees
+                let down_right = Kernel::from_rows( 2, 2, vec![-1.0, 0.0, 0.0, 1.0] );
+                let down_left = Kernel::from_rows( 2, 2, vec![0.0, -1.0, 1.0, 0.0] );
+                return Some( ( down_right, down_left, FRAC_PI_4 ) );
+            },
+            Operator::LaplacianOfGaussian | Operator::DifferenceOfGaussians => return None,
+        };
+
+        let kx = Kernel::separable( vec![-1.0, 0.0, 1.0], smoothing.clone() );
+        let ky = Kernel::separable( smoothing, vec![-1.0, 0.0, 1.0] );
+        return Some( ( kx, ky, rotation ) );
+    }
 }
 
-fn atan( vec_one: Vec<Vec<f32>>, vec_two: Vec<Vec<f32>> ) -> Vec<Vec<f32>> {
-    let mut final_vec = vec_one.clone();
-    for x in 0..vec_one.len() {
-        for y in 0..vec_one[x].len() {
-            let val_1 = vec_one[x][y].clone();
-            let va_2 = vec_two[x][y].clone();
+/// Ratio between the wider and narrower blur of a difference of Gaussians. 1.6 is closest to the
+/// Laplacian of Gaussian it approximates.
+const DOG_RATIO: f32 = 1.6;
+
+/// Settings for each stage of the Canny edge detector. Thresholds are fractions of the strongest
+/// gradient in the image.
+#[derive(Debug, Clone)]
+pub struct CannyParams {
+    /// Standard deviation of the Gaussian blur applied first
+    pub sigma: f32,
+    /// Radius of the Gaussian kernel, so the kernel is `2 * radius + 1` wide
+    pub radius: i32,
+    /// Gradients below this are never edges
+    pub low: f32,
+    /// Gradients above this are always edges. Those in between are edges only when connected to
+    /// one above.
+    pub high: f32,
+    pub norm: GradientNorm,
+    /// Ignore `low` and `high` and derive them from the image with Otsu's method
+    pub auto_threshold: bool,
+    /// What the blur and gradient kernels see past the edge of the image
+    pub border: Border,
+}
 
-            final_vec[x][y] = ( val_1 / va_2 ).atan();
+impl Default for CannyParams {
+    fn default() -> CannyParams {
+        CannyParams {
+            sigma: 1.0,
+            radius: CannyParams::radius_for( 1.0 ),
+            low: 0.05,
+            high: 0.75,
+            norm: GradientNorm::L2,
+            auto_threshold: false,
+            border: Border::Replicate,
         }
     }
-    return final_vec;
-}
-
-fn div_mul(val: f32, i: f32, j: f32) -> f32 {
-    val / i * j
 }
 
-fn mul_div(val: f32, i: f32, j: f32) -> f32 {
-    val * i / j
+impl CannyParams {
+    /// Radius that covers the Gaussian out to three standard deviations.
+    pub fn radius_for( sigma: f32 ) -> i32 {
+        std::cmp::max( ( 3.0 * sigma ).ceil() as i32, 1 )
+    }
 }
 
 // -------------------------------------------------------------------------------------------------
 // Main processes
 // -------------------------------------------------------------------------------------------------
 
-/// Function to sharpen an image by convolution
-#[allow(dead_code)]
-fn sharpen(vec: &Vec<Vec<f32>> ) -> Vec<Vec<f32>> {
-    let kernel = vec![
-        vec![-0.5, -1.0, -0.5],
-        vec![-1.0, 7.0, -1.0],
-        vec![-0.5, -1.0, -0.5]
-    ];
-
-    return convolve( vec, kernel );
+fn blur( image: &ImageF32, sigma: f32, radius: i32, border: Border ) -> ImageF32 {
+    return convolution::convolve( image, &Kernel::gaussian( sigma, radius as usize ), border );
 }
 
-fn sobel_process( vec: &Vec<Vec<f32>>, i: f32, j: f32 ) -> Vec<Vec<f32>> {
-    let mut final_vec = vec.clone();
-    for x in 0..vec.len() {
-        for y in 0..vec[x].len() {
-            let val_1 = vec[x][y].clone();
-            final_vec[x][y] = div_mul( val_1, i, j );
-        }
+/// Gradient magnitude, scaled so the strongest is 1, and direction in radians from the x axis.
+/// `kx` and `ky` measure the gradient along axes turned by `rotation` from x and y. Several
+/// channels are combined through their structure tensor, which always uses the L2 norm.
+fn gradient_filters( channels: &[ImageF32], kx: &Kernel, ky: &Kernel, rotation: f32, norm: GradientNorm, border: Border ) -> ( ImageF32, ImageF32 ) {
+    let ( mut magnitude, mut theta ) = match channels {
+        [image] => {
+            let gradient_x = convolution::convolve( image, kx, border );
+            let gradient_y = convolution::convolve( image, ky, border );
+
+            let magnitude = match norm {
+                GradientNorm::L1 => combine( &gradient_x, &gradient_y, simd::abs_sum ),
+                GradientNorm::L2 => combine( &gradient_x, &gradient_y, simd::hypot ),
+            };
+            ( magnitude, combine( &gradient_y, &gradient_x, simd::atan2 ) )
+        },
+        _ => structure_tensor( channels, kx, ky, border ),
+    };
+
+    let max = magnitude.max();
+    if max > 0.0 {
+        magnitude.map_in_place( |value| value / max );
+    }
+
+    if rotation != 0.0 {
+        theta.map_in_place( |angle| ( angle + rotation + PI ).rem_euclid( 2.0 * PI ) - PI );
     }
-    return final_vec;
+
+    return ( magnitude, theta );
 }
 
-fn angle_process( vec: Vec<Vec<f32>>, i: f32, j: f32 ) -> Vec<Vec<f32>> {
-    let mut final_vec = vec.clone();
-    for x in 0..vec.len() {
-        for y in 0..vec[x].len() {
-            let val_1 = vec[x][y].clone();
+/// Gradient magnitude and direction across several channels at once, as described by Di Zenzo in
+/// "A note on the gradient of a multi-image". The direction is the one the channels change
+/// fastest in together, i.e. the main eigenvector of the sum of each channel's gradient outer
+/// product, and the magnitude the square root of its eigenvalue. Unlike adding up each channel's
+/// magnitude, opposite changes in two channels don't cancel out, so edges between colours of the
+/// same brightness are found. The eigenvector is only a line, so it is pointed the way the
+/// channel gradients point on the whole.
+fn structure_tensor( channels: &[ImageF32], kx: &Kernel, ky: &Kernel, border: Border ) -> ( ImageF32, ImageF32 ) {
+    let gradients: Vec<( ImageF32, ImageF32 )> = channels.iter()
+        .map( |channel| ( convolution::convolve( channel, kx, border ), convolution::convolve( channel, ky, border ) ) )
+        .collect();
+
+    let ( width, height ) = ( channels[0].width(), channels[0].height() );
+    let mut magnitude = ImageF32::new( width, height );
+    let mut theta = ImageF32::new( width, height );
+
+    magnitude.par_rows_mut().zip( theta.par_rows_mut() ).for_each( |( ( y, magnitude_row ), ( _, theta_row ) )| {
+        for x in 0..width {
+            let ( mut xx, mut yy, mut xy, mut sum_x, mut sum_y ) = ( 0.0f32, 0.0f32, 0.0f32, 0.0, 0.0 );
+            for ( gradient_x, gradient_y ) in gradients.iter() {
+                let ( gx, gy ) = ( gradient_x[( x, y )], gradient_y[( x, y )] );
+                xx += gx * gx;
+                yy += gy * gy;
+                xy += gx * gy;
+                sum_x += gx;
+                sum_y += gy;
+            }
+
+            let eigenvalue = 0.5 * ( xx + yy + ( ( xx - yy ).powi( 2 ) + 4.0 * xy * xy ).sqrt() );
+            let mut angle = 0.5 * ( 2.0 * xy ).atan2( xx - yy );
+            if angle.cos() * sum_x + angle.sin() * sum_y < 0.0 {
+                angle -= PI.copysign( angle );
+            }
 
-            final_vec[x][y] = mul_div( val_1, i, j );
+            magnitude_row[x] = eigenvalue.sqrt();
+            theta_row[x] = angle;
         }
-    }
-    return final_vec;
+    } );
+
+    return ( magnitude, theta );
 }
 
-fn sobel_filters(vec: Vec<Vec<f32>>) -> (Vec<Vec<f32>>, Vec<Vec<f32>>){
-    let kx: Vec<Vec<f32>> = vec![
-        vec![-1.0, 0.0, 1.0],
-        vec![-2.0, 0.0, 2.0],
-        vec![-1.0, 0.0, 1.0]
-    ];
-    let ky: Vec<Vec<f32>> = vec![
-        vec![1.0, 2.0, 1.0],
-        vec![0.0, 0.0, 0.0],
-        vec![-1.0, -2.0, -1.0]
-    ];
-
-    let hor_vec = convolve(&vec, kx);
-    let vert_vec = convolve(&vec, ky);
-
-    let hypot_vec = hypot(&hor_vec, &vert_vec);
-    let max = flatten(hypot_vec.clone()).iter().cloned().fold(0./0., f32::max);
-    let processed = sobel_process(&hypot_vec, max, 1.0 );
-    let theta = atan(hor_vec.clone(), vert_vec.clone());
-
-    return (processed, theta);
+/// Keep only pixels that are at least as strong as both neighbours across the edge, i.e. along
+/// the gradient direction rounded to the nearest 45 degrees.
+fn non_max_suppression( magnitude: &ImageF32, theta: &ImageF32 ) -> ImageF32 {
+    let mut output = ImageF32::new( magnitude.width(), magnitude.height() );
+    let ( width, height ) = ( magnitude.width(), magnitude.height() );
+
+    output.par_rows_mut().for_each( |( y, row )| {
+        if y == 0 || y + 1 >= height {
+            return;
+        }
+
+        for x in 1..width.saturating_sub( 1 ) {
+            let mut angle = theta[( x, y )].to_degrees();
+            if angle < 0.0 {
+                angle += 180.0
+            }
+
+            let ( q, r ) = if !( 22.5..157.5 ).contains( &angle ) {
+                ( magnitude[( x + 1, y )], magnitude[( x - 1, y )] )
+            } else if angle < 67.5 {
+                ( magnitude[( x + 1, y + 1 )], magnitude[( x - 1, y - 1 )] )
+            } else if angle < 112.5 {
+                ( magnitude[( x, y + 1 )], magnitude[( x, y - 1 )] )
+            } else {
+                ( magnitude[( x - 1, y + 1 )], magnitude[( x + 1, y - 1 )] )
+            };
+
+            let value = magnitude[( x, y )];
+            if value >= q && value >= r {
+                row[x] = value;
+            }
+        }
+    } );
+
+    return output;
 }
 
-/// Generate a Gaussian kernel for applying to image vectors.
-fn gaussian_kernel( radius: i32 ) -> Vec<Vec<f32>> {
-    let sigma = std::cmp::max( radius / 2, 1);
-    let kernel_width = (2 * radius) + 1;
-    let mut kernel: Vec<Vec<f32>> = vec![vec![0.0;kernel_width as usize];kernel_width as usize];
-    let denominator = (2 * sigma * sigma) as f32;
-    let pi: f32 = std::f64::consts::PI as f32;
-    let mut sum = 0.0;
-
-    for x in -radius..radius {
-        for y in -radius..radius {
-            let numerator = -( x * x + y * y ) as f32;
-            let kernel_val = ( (numerator / denominator).exp() ) / (pi * denominator);
-            kernel[(x + radius) as usize][(y + radius) as usize] = kernel_val;
-            sum += kernel_val;
+/// Threshold that best splits the non-zero values into two classes, by Otsu's method.
+fn otsu( image: &ImageF32 ) -> f32 {
+    let bins = 256;
+    let mut histogram = vec![0usize; bins];
+    let mut total = 0;
+
+    for value in image.values() {
+        if *value > 0.0 {
+            histogram[( value.clamp( 0.0, 1.0 ) * ( bins - 1 ) as f32 ) as usize] += 1;
+            total += 1;
         }
-    };
+    }
 
-    for i in 0..kernel_width as usize {
-        for j in 0..kernel_width as usize {
-            kernel[i][j] /= sum;
+    let sum: f32 = histogram.iter().enumerate().map( |( bin, count )| bin as f32 * *count as f32 ).sum();
+    let mut background_sum = 0.0;
+    let mut background_count = 0;
+    let mut best = ( 0, 0.0 );
+
+    for ( bin, count ) in histogram.iter().enumerate() {
+        background_count += count;
+        if background_count == 0 {
+            continue;
+        }
+        let foreground_count = total - background_count;
+        if foreground_count == 0 {
+            break;
+        }
+
+        background_sum += bin as f32 * *count as f32;
+        let background_mean = background_sum / background_count as f32;
+        let foreground_mean = ( sum - background_sum ) / foreground_count as f32;
+        let variance = background_count as f32 * foreground_count as f32 * ( background_mean - foreground_mean ).powi( 2 );
+
+        if variance > best.1 {
+            best = ( bin, variance );
         }
     }
 
-    return kernel;
+    return best.0 as f32 / ( bins - 1 ) as f32;
 }
 
-fn non_max_suppression( vec: Vec<Vec<f32>>, theta: Vec<Vec<f32>> ) -> Vec<Vec<f32>> {
-    let mut final_vec = vec.clone();
-    let mut angle = angle_process( theta, 180.0, std::f32::consts::PI );
+/// Mark where `response` changes sign between neighbours, on whichever side is closer to zero,
+/// as long as the jump across is at least `threshold` of the largest jump in the image.
+fn zero_crossings( response: &ImageF32, threshold: f32 ) -> ImageF32 {
+    let ( width, height ) = ( response.width(), response.height() );
 
-    for i in 1..vec.len()-1 {
-        for j in 1..vec[i].len()-1 {
-            let mut q = 255.0;
-            let mut r = 255.0;
+    // Jump to the right and below each pixel, or 0 if the sign doesn't change
+    let jump = |x: usize, y: usize, nx: usize, ny: usize| {
+        let ( value, neighbour ) = ( response[( x, y )], response[( nx, ny )] );
+        if ( value < 0.0 ) != ( neighbour < 0.0 ) { ( value - neighbour ).abs() } else { 0.0 }
+    };
 
-            if i > vec.len() || j > vec[i].len() {
-                final_vec[i][j] = 0.0
-            } else {
-                if angle[i][j] < 0.0 {
-                    angle[i][j] += 180.0
-                }
+    let mut largest = 0.0f32;
+    for y in 0..height {
+        for x in 0..width {
+            if x + 1 < width {
+                largest = largest.max( jump( x, y, x + 1, y ) );
+            }
+            if y + 1 < height {
+                largest = largest.max( jump( x, y, x, y + 1 ) );
+            }
+        }
+    }
+
+    let mut edges = ImageF32::new( width, height );
+    if largest == 0.0 {
+        return edges;
+    }
 
-                if angle[i][j] < 22.5 && angle[i][j] >= 0.0 || angle[i][j] <= 180.0 && angle[i][j] >= 157.5 {
-                    q = vec[i][j + 1];
-                    r = vec[i][j - 1];
-                    // angle 45
-                } else if angle[i][j] >= 22.5 && angle[i][j] < 67.5 {
-                    q = vec[i + 1][j - 1];
-                    r = vec[i - 1][j + 1];
-                    // angle 90
-                } else if angle[i][j] >= 67.5 && angle[i][j] < 112.5 {
-                    q = vec[i + 1][j];
-                    r = vec[i - 1][j];
-                    // angle 135
-                } else if angle[i][j] >= 112.5 && angle[i][j] < 157.5 {
-                    q = vec[i - 1][j - 1];
-                    r = vec[i + 1][j + 1];
+    let minimum = threshold * largest;
+    for y in 0..height {
+        for x in 0..width {
+            for ( nx, ny ) in [( x + 1, y ), ( x, y + 1 )] {
+                if nx >= width || ny >= height || jump( x, y, nx, ny ) < minimum {
+                    continue;
                 }
 
-                if vec[i][j] >= q && vec[i][j] >= r {
-                    final_vec[i][j] = vec[i][j]
+                if response[( x, y )].abs() <= response[( nx, ny )].abs() {
+                    edges[( x, y )] = 1.0;
                 } else {
-                    final_vec[i][j] = 0.0
+                    edges[( nx, ny )] = 1.0;
                 }
             }
         }
     }
-    return final_vec;
+
+    return edges;
 }
 
-fn threshold( mut vec: Vec<Vec<f32>> ) -> Vec<Vec<f32>> {
-    let low_threshold = 0.05;
-    let high_threshold = 0.75;
-
-    for i in 1..vec.len() {
-        for j in 1..vec[i].len() {
-            if vec[i][j] >= high_threshold {
-                vec[i][j] = 1.0;
-            } else if vec[i][j] >= low_threshold && vec[i][j] < high_threshold {
-                vec[i][j] = 0.3
-            } else if vec[i][j] < low_threshold {
-                vec[i][j] = 0.0
-            }
+/// Laplacian of the image blurred by `sigma`.
+fn laplacian_of_gaussian( image: &ImageF32, params: &CannyParams ) -> ImageF32 {
+    let laplacian = Kernel::from_rows( 3, 3, vec![
+        0.0, 1.0, 0.0,
+        1.0, -4.0, 1.0,
+        0.0, 1.0, 0.0
+    ] );
+
+    let blurred = blur( image, params.sigma, params.radius, params.border );
+    return convolution::convolve( &blurred, &laplacian, params.border );
+}
+
+/// The image blurred by `sigma` less the image blurred by `DOG_RATIO` times as much.
+fn difference_of_gaussians( image: &ImageF32, params: &CannyParams ) -> ImageF32 {
+    let wide_radius = ( params.radius as f32 * DOG_RATIO ).ceil() as i32;
+
+    let mut narrow = blur( image, params.sigma, params.radius, params.border );
+    let wide = blur( image, params.sigma * DOG_RATIO, wide_radius, params.border );
+    narrow.par_rows_mut().for_each( |( y, row )| {
+        for ( value, wide_value ) in row.iter_mut().zip( wide.row( y ) ) {
+            *value -= wide_value;
         }
-    }
+    } );
+
+    return narrow;
+}
 
-    return vec;
+const WEAK: f32 = 0.3;
+const STRONG: f32 = 1.0;
+
+fn threshold( image: &mut ImageF32, low_threshold: f32, high_threshold: f32 ) {
+    image.map_in_place( |value| {
+        if value >= high_threshold {
+            STRONG
+        } else if value >= low_threshold {
+            WEAK
+        } else {
+            0.0
+        }
+    } );
 }
 
-fn hysteresis( mut vec: Vec<Vec<f32>> ) -> Vec<Vec<f32>> {
-    let weak: f32 = 0.3;
-    let strong: f32 = 1.0;
+/// Promote weak pixels that are connected to a strong pixel, directly or through other weak
+/// pixels, and drop the rest. Growing outwards from every strong pixel reaches the same result
+/// as sweeping the image until no more weak pixels get promoted, in a single pass.
+fn hysteresis( image: &mut ImageF32 ) {
+    let width = image.width() as isize;
+    let height = image.height() as isize;
+
+    let mut stack: Vec<(isize, isize)> = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            if image[( x as usize, y as usize )] == STRONG {
+                stack.push( ( x, y ) );
+            }
+        }
+    }
+
+    while let Some( ( x, y ) ) = stack.pop() {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                let ( nx, ny ) = ( x + dx, y + dy );
+                if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                    continue;
+                }
 
-    for i in 1..vec.len()-1 {
-        for j in 1..vec[i].len()-1 {
-            if vec[i][j] == weak {
-                if (vec[i+1][j-1] == strong) || (vec[i+1][j] == strong) || (vec[i+1][j+1] == strong) || (vec[i][j-1] == strong) || (vec[i][j+1] == strong) || (vec[i-1][j-1] == strong) || (vec[i-1][j] == strong) || (vec[i-1][j+1] == strong) {
-                    vec[i][j] = strong
-                } else {
-                    vec[i][j] = strong
+                let neighbour = &mut image[( nx as usize, ny as usize )];
+                if *neighbour == WEAK {
+                    *neighbour = STRONG;
+                    stack.push( ( nx, ny ) );
                 }
             }
         }
     }
 
-    return vec;
+    image.map_in_place( |value| if value == STRONG { STRONG } else { 0.0 } );
 }
 
 // -------------------------------------------------------------------------------------------------
 // Utilised function
 // -------------------------------------------------------------------------------------------------
 
-pub fn detect( vec: Vec<Vec<f32>> ) -> Vec<Vec<f32>> {
-    let radius = 2;
-    let padded_vec = add_padding( vec, radius );
+/// Canny edge detection on a grayscale image from 0 to 1, or across several channels at once so
+/// that an edge in any of them counts. Edges are 1 and everything else 0.
+pub fn detect( channels: &[ImageF32], params: &CannyParams ) -> ImageF32 {
+    let ( magnitude, theta ) = gradients( channels, Operator::Canny, params ).expect( "Canny measures a gradient" );
+    let mut edges = non_max_suppression( &magnitude, &theta );
+
+    let ( low, high ) = if params.auto_threshold {
+        let high = otsu( &edges );
+        ( high / 2.0, high )
+    } else {
+        ( params.low, params.high )
+    };
+    threshold( &mut edges, low, high );
+    hysteresis( &mut edges );
+
+    return edges;
+}
+
+/// Gradient magnitude, scaled so the strongest is 1, and direction in radians, as measured by
+/// `operator` across every channel. Canny blurs first, the others measure the image as it is.
+/// `None` for the Laplacian and difference of Gaussians, which don't measure a gradient.
+pub fn gradients( channels: &[ImageF32], operator: Operator, params: &CannyParams ) -> Option<( ImageF32, ImageF32 )> {
+    let ( kx, ky, rotation ) = operator.gradient_kernels()?;
+
+    if operator == Operator::Canny {
+        let blurred: Vec<ImageF32> = channels.iter()
+            .map( |channel| blur( channel, params.sigma, params.radius, params.border ) )
+            .collect();
+        return Some( gradient_filters( &blurred, &kx, &ky, rotation, params.norm, params.border ) );
+    }
+    return Some( gradient_filters( channels, &kx, &ky, rotation, params.norm, params.border ) );
+}
+
+/// Run any of the edge detectors on a grayscale image from 0 to 1, or on several channels at
+/// once. Canny and the zero crossing operators give 1 on edges and 0 elsewhere, the rest give the
+/// gradient magnitude. The zero crossing operators blur by `params.sigma`, drop crossings weaker
+/// than `params.low` and only work on a single channel, returning an error for more.
+pub fn detect_with( channels: &[ImageF32], operator: Operator, params: &CannyParams ) -> Result<ImageF32, String> {
+    let single = || match channels {
+        [image] => Ok( image ),
+        _ => Err( format!( "{operator:?} only works on grayscale images, not {} channels", channels.len() ) ),
+    };
+
+    return Ok( match operator {
+        Operator::Canny => detect( channels, params ),
+        Operator::LaplacianOfGaussian => zero_crossings( &laplacian_of_gaussian( single()?, params ), params.low ),
+        Operator::DifferenceOfGaussians => zero_crossings( &difference_of_gaussians( single()?, params ), params.low ),
+        _ => gradients( channels, operator, params ).expect( "Operator measures a gradient" ).0,
+    } );
+}
+
+/// The channels of a colour image to find edges across. RGB goes from 0 to 1 and Lab is in its
+/// usual units, with lightness from 0 to 100.
+pub fn color_channels( image: &DynamicImage, space: ColorSpace ) -> Vec<ImageF32> {
+    let rgb = image.to_rgb32f();
+    let ( width, height ) = ( rgb.width() as usize, rgb.height() as usize );
+
+    let pixels: Vec<[f32; 3]> = match space {
+        ColorSpace::Rgb => rgb.pixels().map( |pixel| pixel.0 ).collect(),
+        ColorSpace::Lab => rgb.pixels().map( |pixel| srgb_to_lab( pixel.0 ) ).collect(),
+    };
+
+    return ( 0..3 )
+        .map( |channel| ImageF32::from_vec( width, height, pixels.iter().map( |pixel| pixel[channel] ).collect() ) )
+        .collect();
+}
+
+/// Colour every pixel by the direction of its gradient, going once around the colour wheel from
+/// red pointing right, with the brightness showing how strong it is.
+pub fn direction_image( magnitude: &ImageF32, theta: &ImageF32 ) -> RgbImage {
+    let mut output = RgbImage::new( magnitude.width() as u32, magnitude.height() as u32 );
+    for ( x, y, pixel ) in output.enumerate_pixels_mut() {
+        let ( x, y ) = ( x as usize, y as usize );
+        *pixel = hue_to_rgb( theta[( x, y )].to_degrees(), magnitude[( x, y )] );
+    }
+
+    return output;
+}
+// -------------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_crossing_operators_reject_colour() {
+        let square = ImageF32::from_fn( 32, 32, |x, y| if ( 8..24 ).contains( &x ) && ( 8..24 ).contains( &y ) { 1.0 } else { 0.0 } );
+        let params = CannyParams::default();
 
-    let kernel = gaussian_kernel( radius );
+        for operator in [Operator::LaplacianOfGaussian, Operator::DifferenceOfGaussians] {
+            let edges = detect_with( std::slice::from_ref( &square ), operator, &params ).unwrap();
+            assert!( edges.values().any( |value| *value > 0.0 ), "{operator:?} found no edges" );
 
-    let new_image_vec = convolve( &padded_vec, kernel );
-    let gradient_theta = sobel_filters( new_image_vec );
-    let suppressed = non_max_suppression( gradient_theta.0, gradient_theta.1 );
-    let filtered = threshold( suppressed );
-    let leveled = hysteresis( filtered );
-    let unpadded_vec = remove_padding( leveled, radius );
+            let colour = vec![square.clone(), square.clone(), square.clone()];
+            assert!( detect_with( &colour, operator, &params ).is_err() );
+        }
+    }
+
+    #[test]
+    fn edges_are_the_same_on_any_number_of_threads() {
+        let image = ImageF32::from_fn( 97, 61, |x, y| ( ( x as f32 / 7.0 ).sin() * ( y as f32 / 5.0 ).cos() + 1.0 ) / 2.0 );
+        let detect_on = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads( threads ).build().unwrap();
+            pool.install( || detect( std::slice::from_ref( &image ), &CannyParams::default() ) )
+        };
+
+        let single = detect_on( 1 );
+        assert!( single.values().any( |value| *value > 0.0 ) );
+        for threads in [2, 4, 7] {
+            assert_eq!( detect_on( threads ), single, "{threads} threads" );
+        }
+    }
+
+    /// The green with exactly the same luma as `red`, found by stepping through the floats next to
+    /// the ideal one, since any difference at all would be stretched to a full strength edge.
+    fn isoluminant_green( red: f32 ) -> f32 {
+        let luma = |pixel: [f32; 3]| ImageF32::from_luma( &DynamicImage::ImageRgb32F( image::Rgb32FImage::from_pixel( 1, 1, image::Rgb( pixel ) ) ) )[( 0, 0 )];
+        let target = luma( [red, 0.0, 0.0] );
+        let ideal = target / 0.7152;
+
+        ( ideal.to_bits() - 64..ideal.to_bits() + 64 )
+            .map( f32::from_bits )
+            .find( |green| luma( [0.0, *green, 0.0] ) == target )
+            .expect( "No green has exactly the same luma" )
+    }
 
-    return unpadded_vec;
+    #[test]
+    fn structure_tensor_finds_edges_between_colours_of_the_same_brightness() {
+        let green = isoluminant_green( 0.75 );
+        let image = DynamicImage::ImageRgb32F( image::Rgb32FImage::from_fn( 32, 32, |x, _| {
+            if x < 16 { image::Rgb( [0.75, 0.0, 0.0] ) } else { image::Rgb( [0.0, green, 0.0] ) }
+        } ) );
+        let params = CannyParams::default();
+
+        // The brightness is the same everywhere, so there's nothing for the gray path to find
+        let gray = detect( &[ImageF32::from_luma( &image )], &params );
+        assert!( gray.values().all( |value| *value == 0.0 ) );
+
+        for space in [ColorSpace::Rgb, ColorSpace::Lab] {
+            let channels = color_channels( &image, space );
+            let edges = detect( &channels, &params );
+
+            for y in 4..28 {
+                let row: Vec<usize> = ( 0..32 ).filter( |x| edges[( *x, y )] > 0.0 ).collect();
+                assert!( !row.is_empty() && row.iter().all( |x| ( 14..18 ).contains( x ) ), "{space:?} row {y}: {row:?}" );
+            }
+
+            // Across the edge, pointing the same way along every row
+            let ( magnitude, theta ) = gradients( &channels, Operator::Canny, &params ).unwrap();
+            assert_eq!( magnitude[( 15, 16 )], 1.0, "{space:?}" );
+            assert!( theta[( 15, 16 )].sin().abs() < 1e-3, "{space:?} points at {}", theta[( 15, 16 )] );
+            assert!( ( 0..32 ).all( |y| theta[( 15, y )] == theta[( 15, 16 )] ) );
+        }
+    }
+
+    #[test]
+    fn hysteresis_keeps_weak_pixels_connected_to_strong_ones() {
+        // A strong pixel with a chain of weak ones running diagonally off it, and a weak pixel
+        // on its own
+        let mut image = ImageF32::new( 8, 4 );
+        image[( 0, 0 )] = 0.9;
+        image[( 1, 1 )] = 0.4;
+        image[( 2, 2 )] = 0.3;
+        image[( 3, 2 )] = 0.2;
+        image[( 6, 1 )] = 0.5;
+        image[( 7, 3 )] = 0.1;
+
+        threshold( &mut image, 0.2, 0.75 );
+        hysteresis( &mut image );
+
+        let edges: Vec<( usize, usize )> = ( 0..4 )
+            .flat_map( |y| ( 0..8 ).map( move |x| ( x, y ) ) )
+            .filter( |( x, y )| image[( *x, *y )] == STRONG )
+            .collect();
+        assert_eq!( edges, vec![( 0, 0 ), ( 1, 1 ), ( 2, 2 ), ( 3, 2 )] );
+        assert!( image.values().all( |value| *value == 0.0 || *value == STRONG ) );
+    }
+
+    #[test]
+    fn otsu_splits_a_bimodal_image() {
+        use rand::{ Rng, SeedableRng };
+
+        // Two spread out peaks, and a lot of zeros that Otsu's method has to ignore
+        let mut rng = rand::rngs::StdRng::seed_from_u64( 1 );
+        let values: Vec<f32> = ( 0..64 * 64 ).map( |i| match i % 4 {
+            0 => rng.gen_range( 0.15..0.25 ),
+            1 => rng.gen_range( 0.65..0.75 ),
+            _ => 0.0,
+        } ).collect();
+        let image = ImageF32::from_vec( 64, 64, values );
+
+        // The split is the top of the lower class, so it lands on the last bin of the lower peak
+        let split = otsu( &image );
+        assert!( ( 0.24..0.65 ).contains( &split ), "split at {split}" );
+    }
 }
-// -------------------------------------------------------------------------------------------------
\ No newline at end of file
@@ -0,0 +1,249 @@
+use image::{DynamicImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+use crate::convolution::{self, Border, Kernel};
+use crate::image_f32::ImageF32;
+
+// -------------------------------------------------------------------------------------------------
+// Filters
+// -------------------------------------------------------------------------------------------------
+//
+// Every filter works on a single channel from 0 to 255, and colour images are filtered one
+// channel at a time. Alpha is left as it is.
+
+/// A filter and its settings. Radii are in pixels either side of the centre, so a radius of 1 is
+/// a 3x3 window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    /// Gaussian blur
+    Gaussian { sigma: f32, radius: usize },
+    /// Mean of a square window
+    Box { radius: usize },
+    /// 3x3 kernel boosting the centre pixel against its neighbours
+    Sharpen,
+    /// Add back `amount` times the difference between the image and its Gaussian blur
+    Unsharp { sigma: f32, radius: usize, amount: f32 },
+    /// Median of a square window, which removes speckles while keeping edges sharp
+    Median { radius: usize },
+    /// Gaussian blur that only averages pixels with similar values, so edges are kept.
+    /// `range_sigma` is how far apart two values can be, out of 255, and still be averaged.
+    Bilateral { sigma: f32, radius: usize, range_sigma: f32 },
+}
+
+impl Filter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::Gaussian { .. } => "gaussian",
+            Filter::Box { .. } => "box",
+            Filter::Sharpen => "sharpen",
+            Filter::Unsharp { .. } => "unsharp",
+            Filter::Median { .. } => "median",
+            Filter::Bilateral { .. } => "bilateral",
+        }
+    }
+
+    /// Filter a single channel.
+    pub fn apply(&self, channel: &ImageF32, border: Border) -> ImageF32 {
+        match *self {
+            Filter::Gaussian { sigma, radius } => convolution::convolve(channel, &Kernel::gaussian(sigma, radius), border),
+            Filter::Box { radius } => convolution::convolve(channel, &Kernel::box_filter(2 * radius + 1), border),
+            Filter::Sharpen => sharpen(channel, border),
+            Filter::Unsharp { sigma, radius, amount } => unsharp(channel, sigma, radius, amount, border),
+            Filter::Median { radius } => median(channel, radius, border),
+            Filter::Bilateral { sigma, radius, range_sigma } => bilateral(channel, sigma, radius, range_sigma, border),
+        }
+    }
+}
+
+/// Sharpen by convolution with a kernel that sums to 1, so flat areas stay the same.
+fn sharpen(channel: &ImageF32, border: Border) -> ImageF32 {
+    let kernel = Kernel::from_rows(3, 3, vec![
+        -0.5, -1.0, -0.5,
+        -1.0, 7.0, -1.0,
+        -0.5, -1.0, -0.5
+    ]);
+
+    convolution::convolve(channel, &kernel, border)
+}
+
+fn unsharp(channel: &ImageF32, sigma: f32, radius: usize, amount: f32, border: Border) -> ImageF32 {
+    let blurred = convolution::convolve(channel, &Kernel::gaussian(sigma, radius), border);
+
+    ImageF32::from_fn(channel.width(), channel.height(), |x, y| {
+        let value = channel[(x, y)];
+        value + amount * (value - blurred[(x, y)])
+    })
+}
+
+fn median(channel: &ImageF32, radius: usize, border: Border) -> ImageF32 {
+    let size = 2 * radius + 1;
+    let padded = convolution::pad(channel, radius, radius, radius, radius, border);
+
+    let mut output = ImageF32::new(channel.width(), channel.height());
+    output.par_rows_mut().for_each(|(y, row)| {
+        let mut window = Vec::with_capacity(size * size);
+
+        for (x, value) in row.iter_mut().enumerate() {
+            window.clear();
+            for window_y in y..y + size {
+                window.extend_from_slice(&padded.row(window_y)[x..x + size]);
+            }
+
+            let middle = window.len() / 2;
+            *value = *window.select_nth_unstable_by(middle, f32::total_cmp).1;
+        }
+    });
+
+    output
+}
+
+fn bilateral(channel: &ImageF32, sigma: f32, radius: usize, range_sigma: f32, border: Border) -> ImageF32 {
+    // Either sigma at 0 leaves only the centre pixel, or pixels equal to it, to average, which
+    // is the image as it is. Computing the weights would divide by zero.
+    if sigma <= 0.0 || range_sigma <= 0.0 {
+        return channel.clone();
+    }
+
+    let size = 2 * radius + 1;
+    let padded = convolution::pad(channel, radius, radius, radius, radius, border);
+
+    // The spatial weights are the same at every pixel, only the range weights change
+    let spatial = ImageF32::from_fn(size, size, |x, y| {
+        let (dx, dy) = (x as f32 - radius as f32, y as f32 - radius as f32);
+        (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+    });
+    let range_scale = -1.0 / (2.0 * range_sigma * range_sigma);
+
+    let mut output = ImageF32::new(channel.width(), channel.height());
+    output.par_rows_mut().for_each(|(y, row)| {
+        for (x, value) in row.iter_mut().enumerate() {
+            let centre = padded[(x + radius, y + radius)];
+            let (mut sum, mut total_weight) = (0.0, 0.0);
+
+            for window_y in 0..size {
+                let padded_row = &padded.row(y + window_y)[x..x + size];
+                for (neighbour, spatial_weight) in padded_row.iter().zip(spatial.row(window_y)) {
+                    let difference = neighbour - centre;
+                    let weight = spatial_weight * (difference * difference * range_scale).exp();
+                    sum += neighbour * weight;
+                    total_weight += weight;
+                }
+            }
+
+            // The centre always has a weight of 1, so the total is never 0
+            *value = sum / total_weight;
+        }
+    });
+
+    output
+}
+
+// -------------------------------------------------------------------------------------------------
+// Images
+// -------------------------------------------------------------------------------------------------
+
+/// Apply the filter to each colour channel of the image, keeping its alpha if it has any.
+pub fn filter_image(image: &DynamicImage, filter: &Filter, border: Border) -> DynamicImage {
+    map_channels(image, |channel| filter.apply(channel, border))
+}
+
+/// Process each colour channel of the image, from 0 to 255, separately, keeping its alpha if it
+/// has any.
+pub fn map_channels(image: &DynamicImage, f: impl Fn(&ImageF32) -> ImageF32) -> DynamicImage {
+    let rgba = image.to_rgba8();
+    let channels: Vec<ImageF32> = (0..3)
+        .map(|channel| f(&ImageF32::from_channel(&rgba, channel)))
+        .collect();
+
+    let filtered = from_channels(&channels, &rgba);
+    if image.color().has_alpha() {
+        DynamicImage::ImageRgba8(filtered)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(filtered).to_rgb8())
+    }
+}
+
+/// Rebuild an RGBA image from filtered colour channels from 0 to 255, taking alpha from
+/// `original`.
+fn from_channels(channels: &[ImageF32], original: &RgbaImage) -> RgbaImage {
+    let mut output = RgbaImage::new(original.width(), original.height());
+
+    for (x, y, pixel) in output.enumerate_pixels_mut() {
+        let (x, y) = (x as usize, y as usize);
+        let value = |channel: usize| channels[channel][(x, y)].round().clamp(0.0, 255.0) as u8;
+        let alpha = original.get_pixel(x as u32, y as u32).0[3];
+
+        *pixel = Rgba([value(0), value(1), value(2), alpha]);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard() -> ImageF32 {
+        ImageF32::from_fn(16, 16, |x, y| if (x / 4 + y / 4) % 2 == 0 { 50.0 } else { 200.0 })
+    }
+
+    fn values(image: &ImageF32) -> Vec<f32> {
+        image.values().copied().collect()
+    }
+
+    #[test]
+    fn zero_sigma_leaves_the_image_as_it_is() {
+        let image = checkerboard();
+        let filters = [
+            Filter::Gaussian { sigma: 0.0, radius: 3 },
+            Filter::Unsharp { sigma: 0.0, radius: 3, amount: 1.0 },
+            Filter::Bilateral { sigma: 0.0, radius: 3, range_sigma: 25.0 },
+            Filter::Bilateral { sigma: 2.0, radius: 3, range_sigma: 0.0 },
+        ];
+
+        for filter in filters {
+            assert_eq!(values(&filter.apply(&image, Border::Reflect)), values(&image), "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn smoothing_filters_keep_flat_areas() {
+        let flat = ImageF32::filled(12, 12, 90.0);
+        let filters = [
+            Filter::Gaussian { sigma: 1.5, radius: 4 },
+            Filter::Box { radius: 2 },
+            Filter::Sharpen,
+            Filter::Unsharp { sigma: 1.0, radius: 3, amount: 2.0 },
+            Filter::Median { radius: 1 },
+            Filter::Bilateral { sigma: 2.0, radius: 3, range_sigma: 25.0 },
+        ];
+
+        for filter in filters {
+            let filtered = filter.apply(&flat, Border::Replicate);
+            assert!(filtered.values().all(|value| (value - 90.0).abs() < 1e-3), "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn median_removes_speckles() {
+        let mut speckled = checkerboard();
+        speckled[(5, 5)] = 255.0;
+        speckled[(10, 1)] = 0.0;
+
+        let filtered = Filter::Median { radius: 1 }.apply(&speckled, Border::Reflect);
+
+        assert_eq!(filtered[(5, 5)], 50.0);
+        assert_eq!(filtered[(10, 1)], 50.0);
+    }
+
+    #[test]
+    fn bilateral_keeps_edges_that_gaussian_blurs() {
+        let image = checkerboard();
+        let bilateral = Filter::Bilateral { sigma: 2.0, radius: 4, range_sigma: 10.0 }.apply(&image, Border::Reflect);
+        let gaussian = Filter::Gaussian { sigma: 2.0, radius: 4 }.apply(&image, Border::Reflect);
+
+        // Just inside a dark square, next to a bright one
+        assert!((bilateral[(3, 1)] - 50.0).abs() < 1.0);
+        assert!(gaussian[(3, 1)] > 60.0);
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+
+use image::{DynamicImage, GenericImage, GrayImage, ImageBuffer, ImageFormat, Luma, Rgb, RgbImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops;
+use rayon::prelude::*;
+
+use crate::dct::Dct;
+use crate::convolution::{self, Border, Kernel};
+use crate::phash;
+use crate::image_f32::ImageF32;
+
+// -------------------------------------------------------------------------------------------------
+// Copy-move detection
+// -------------------------------------------------------------------------------------------------
+//
+// Exact match detection as described by Fridrich, Soukal and Lukáš in "Detection of Copy-Move
+// Forgery in Digital Images". Every overlapping block is reduced to its quantized low frequency
+// DCT coefficients, so that blocks which look the same end up with the same feature even after
+// mild recompression. Sorting the features lexicographically brings matching blocks next to each
+// other, and a cloned region shows up as many matching pairs sharing the same shift vector.
+
+/// Width and height of the blocks that are compared.
+const BLOCK_SIZE: usize = 8;
+
+/// Width and height of the corner of lowest frequency DCT coefficients kept as the feature.
+const FEATURE_SIZE: usize = 4;
+
+/// Step that coefficients are rounded to. Larger steps survive more recompression but match more
+/// blocks that only look alike.
+const QUANTIZATION_STEP: f32 = 4.0;
+
+/// Blocks flatter than this standard deviation match every other flat block, like sky, so they
+/// are left out.
+const MIN_BLOCK_DEVIATION: f32 = 3.0;
+
+/// Matching blocks closer than this many pixels are most likely the same texture overlapping
+/// itself rather than a clone.
+const MIN_SHIFT: f32 = 16.0;
+
+/// How many block pairs need to share a shift vector for it to count as a cloned region.
+const MIN_VOTES: usize = 64;
+
+/// How many of the following blocks in sorted order each block is compared against.
+const SEARCH_WINDOW: usize = 4;
+
+type Feature = [i16; FEATURE_SIZE * FEATURE_SIZE];
+
+/// A block's position and its quantized feature.
+struct Block {
+    feature: Feature,
+    x: usize,
+    y: usize,
+}
+
+/// A region copied somewhere else in the image, by how far it was moved and how many blocks
+/// were found moved by that much.
+#[derive(Debug)]
+pub struct Shift {
+    pub dx: i32,
+    pub dy: i32,
+    pub votes: usize,
+}
+
+/// Mask of the pixels found to be copied, with both the source and destination of every clone
+/// marked in white, and the shifts that were found.
+pub struct CopyMove {
+    pub mask: GrayImage,
+    pub shifts: Vec<Shift>,
+}
+
+fn deviation(block: &[Vec<f32>]) -> f32 {
+    let count = (BLOCK_SIZE * BLOCK_SIZE) as f32;
+    let mean = block.iter().flatten().sum::<f32>() / count;
+    let variance = block.iter().flatten().map(|value| (value - mean).powi(2)).sum::<f32>() / count;
+    variance.sqrt()
+}
+
+/// The quantized low frequency DCT coefficients of every block with enough texture.
+fn blocks(pixels: &[Vec<f32>]) -> Vec<Block> {
+    let width = pixels.len();
+    let height = pixels[0].len();
+    let dct = Dct::new(BLOCK_SIZE);
+
+    // Collecting keeps the blocks in the same order whatever the number of threads
+    (0..=width - BLOCK_SIZE).into_par_iter().flat_map_iter(|x| {
+        let dct = &dct;
+        (0..=height - BLOCK_SIZE).filter_map(move |y| {
+            let block: Vec<Vec<f32>> = pixels[x..x + BLOCK_SIZE]
+                .iter()
+                .map(|column| column[y..y + BLOCK_SIZE].to_vec())
+                .collect();
+
+            if deviation(&block) < MIN_BLOCK_DEVIATION {
+                return None;
+            }
+
+            // The DCT is unnormalized, so scale it back down to pixel values first
+            let coefficients = dct.process_2d(&block);
+            let mut feature = [0; FEATURE_SIZE * FEATURE_SIZE];
+            for (i, value) in feature.iter_mut().enumerate() {
+                let coefficient = coefficients[i / FEATURE_SIZE][i % FEATURE_SIZE] / BLOCK_SIZE as f32;
+                *value = (coefficient / QUANTIZATION_STEP).round() as i16;
+            }
+
+            Some(Block { feature, x, y })
+        })
+    }).collect()
+}
+
+/// Shift vector from one block to another, flipped so that the same pair of regions gives the
+/// same vector whichever block comes first.
+fn shift(from: &Block, to: &Block) -> (i32, i32) {
+    let dx = to.x as i32 - from.x as i32;
+    let dy = to.y as i32 - from.y as i32;
+
+    if dx < 0 || (dx == 0 && dy < 0) {
+        (-dx, -dy)
+    } else {
+        (dx, dy)
+    }
+}
+
+/// Find regions of the image that were copied and pasted elsewhere in the same image. Images
+/// smaller than a block can't be searched and give an error.
+pub fn copy_move(image: &DynamicImage) -> Result<CopyMove, String> {
+    let gray = image.to_luma8();
+    let (width, height) = gray.dimensions();
+
+    if (width as usize) < BLOCK_SIZE || (height as usize) < BLOCK_SIZE {
+        return Err(format!("Image must be at least {BLOCK_SIZE}x{BLOCK_SIZE} pixels, not {width}x{height}"));
+    }
+
+    let mut blocks = blocks(&phash::columns(&gray));
+    blocks.sort_unstable_by_key(|block| block.feature);
+
+    // Pair up identical neighbours in sorted order and vote for their shift vector
+    let mut pairs: Vec<(usize, usize, (i32, i32))> = Vec::new();
+    let mut votes: HashMap<(i32, i32), usize> = HashMap::new();
+
+    for i in 0..blocks.len() {
+        for j in i + 1..(i + 1 + SEARCH_WINDOW).min(blocks.len()) {
+            if blocks[i].feature != blocks[j].feature {
+                break;
+            }
+
+            let vector = shift(&blocks[i], &blocks[j]);
+            if ((vector.0 * vector.0 + vector.1 * vector.1) as f32).sqrt() < MIN_SHIFT {
+                continue;
+            }
+
+            pairs.push((i, j, vector));
+            *votes.entry(vector).or_insert(0) += 1;
+        }
+    }
+
+    let mut shifts: Vec<Shift> = votes.iter()
+        .filter(|(_, count)| **count >= MIN_VOTES)
+        .map(|((dx, dy), count)| Shift { dx: *dx, dy: *dy, votes: *count })
+        .collect();
+    shifts.sort_by_key(|shift| std::cmp::Reverse(shift.votes));
+
+    // Mark the middle of every matched block, then convolving with a block sized kernel spreads
+    // each mark back over the whole block
+    let mut marks = ImageF32::new(width as usize, height as usize);
+    for (i, j, vector) in pairs.iter() {
+        if votes[vector] < MIN_VOTES {
+            continue;
+        }
+
+        for block in [&blocks[*i], &blocks[*j]] {
+            marks[(block.x + BLOCK_SIZE / 2 - 1, block.y + BLOCK_SIZE / 2 - 1)] = 1.0;
+        }
+    }
+
+    let kernel = Kernel::separable(vec![1.0; BLOCK_SIZE], vec![1.0; BLOCK_SIZE]);
+    let coverage = convolution::convolve(&marks, &kernel, Border::Zero);
+
+    let mut mask = GrayImage::new(width, height);
+    for (x, y, pixel) in mask.enumerate_pixels_mut() {
+        if coverage[(x as usize, y as usize)] > 0.0 {
+            *pixel = Luma([255]);
+        }
+    }
+
+    Ok(CopyMove { mask, shifts })
+}
+
+// -------------------------------------------------------------------------------------------------
+// Recompression
+// -------------------------------------------------------------------------------------------------
+//
+// Saving a JPEG again at the quality it was last saved at barely changes it, while regions that
+// were pasted in from elsewhere or saved at a different quality change by more. Error level
+// analysis recompresses once and shows where the error stands out. JPEG ghosts (Farid, "Exposing
+// Digital Forgeries from JPEG Ghosts") sweep the quality instead, so a region previously saved
+// at a lower quality than the rest shows up dark at that quality.
+
+/// Qualities swept by `jpeg_ghost`.
+pub const GHOST_QUALITIES: [u8; 11] = [50, 55, 60, 65, 70, 75, 80, 85, 90, 95, 100];
+
+/// Width of the Gaussian that JPEG ghost differences are averaged over, so that single noisy
+/// pixels don't hide a region.
+const GHOST_SIGMA: f32 = 4.0;
+
+/// Save the image as a JPEG at `quality` in memory and load it back.
+pub fn recompress(image: &DynamicImage, quality: u8) -> RgbImage {
+    let rgb = image.to_rgb8();
+    let mut bytes = Vec::new();
+
+    JpegEncoder::new_with_quality(&mut bytes, quality)
+        .encode_image(&rgb)
+        .expect("Failed to recompress image");
+
+    image::load_from_memory_with_format(&bytes, ImageFormat::Jpeg)
+        .expect("Failed to load recompressed image")
+        .to_rgb8()
+}
+
+/// Black through red and yellow to white as `value` goes from 0 to 1.
+fn heat(value: f32) -> Rgb<u8> {
+    let value = value.clamp(0.0, 1.0) * 3.0;
+    let red = value.min(1.0);
+    let green = (value - 1.0).clamp(0.0, 1.0);
+    let blue = (value - 2.0).clamp(0.0, 1.0);
+
+    Rgb([(red * 255.0) as u8, (green * 255.0) as u8, (blue * 255.0) as u8])
+}
+
+/// Error level analysis heatmap and the largest difference found.
+pub struct ErrorLevel {
+    pub heatmap: RgbImage,
+    pub max_difference: u8,
+}
+
+/// Recompress at `quality` and colour every pixel by its largest channel difference, amplified
+/// so the largest difference in the image is white.
+pub fn error_level(image: &DynamicImage, quality: u8) -> ErrorLevel {
+    let original = image.to_rgb8();
+    let recompressed = recompress(image, quality);
+
+    let differences: Vec<u8> = original.pixels().zip(recompressed.pixels())
+        .map(|(a, b)| (0..3).map(|channel| a.0[channel].abs_diff(b.0[channel])).max().unwrap())
+        .collect();
+    let max_difference = differences.iter().copied().max().unwrap_or(0);
+    let scale = 1.0 / max_difference.max(1) as f32;
+
+    let mut heatmap = RgbImage::new(original.width(), original.height());
+    for (pixel, difference) in heatmap.pixels_mut().zip(differences.iter()) {
+        *pixel = heat(*difference as f32 * scale);
+    }
+
+    ErrorLevel { heatmap, max_difference }
+}
+
+/// Squared difference from recompressing at `quality`, averaged over channels and a small
+/// neighbourhood and stretched so the smallest is black and the largest white. Also returns the
+/// mean difference over the whole image, which is lowest at the quality it was last saved at.
+pub fn jpeg_ghost(image: &DynamicImage, quality: u8) -> (GrayImage, f32) {
+    let original = image.to_rgb8();
+    let recompressed = recompress(image, quality);
+    let (width, height) = original.dimensions();
+
+    let mut differences: ImageBuffer<Luma<f32>, Vec<f32>> = ImageBuffer::new(width, height);
+    for (pixel, (a, b)) in differences.pixels_mut().zip(original.pixels().zip(recompressed.pixels())) {
+        let sum: f32 = (0..3).map(|channel| (a.0[channel] as f32 - b.0[channel] as f32).powi(2)).sum();
+        // Float images are clamped to 0-1 when blurred
+        *pixel = Luma([sum / 3.0 / (255.0 * 255.0)]);
+    }
+
+    let mean = differences.pixels().map(|pixel| pixel.0[0]).sum::<f32>() / (width * height) as f32;
+    let averaged = imageops::blur(&differences, GHOST_SIGMA);
+    let min = averaged.pixels().map(|pixel| pixel.0[0]).fold(f32::INFINITY, f32::min);
+    let max = averaged.pixels().map(|pixel| pixel.0[0]).fold(f32::NEG_INFINITY, f32::max);
+    let range = (max - min).max(f32::EPSILON);
+
+    let mut ghost = GrayImage::new(width, height);
+    for (pixel, value) in ghost.pixels_mut().zip(averaged.pixels()) {
+        *pixel = Luma([((value.0[0] - min) / range * 255.0) as u8]);
+    }
+
+    (ghost, mean * 255.0 * 255.0)
+}
+
+/// Every quality of the sweep side by side, lowest quality on the left.
+pub fn ghost_sheet(ghosts: &[GrayImage]) -> GrayImage {
+    let (width, height) = ghosts[0].dimensions();
+    let mut sheet = GrayImage::new(width * ghosts.len() as u32, height);
+
+    for (i, ghost) in ghosts.iter().enumerate() {
+        sheet.copy_from(ghost, i as u32 * width, 0).expect("Ghost does not fit in the sheet");
+    }
+
+    sheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    /// Random noise, which has enough texture everywhere that no two blocks match by chance.
+    fn noise(width: u32, height: u32, seed: u64) -> RgbImage {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        RgbImage::from_fn(width, height, |_, _| {
+            let value = rng.gen_range(0..=255);
+            Rgb([value, value, value])
+        })
+    }
+
+    #[test]
+    fn copy_move_finds_a_cloned_patch() {
+        let mut image = noise(128, 128, 1);
+        let patch = imageops::crop_imm(&image, 10, 12, 32, 32).to_image();
+        image.copy_from(&patch, 70, 60).unwrap();
+
+        let result = copy_move(&DynamicImage::ImageRgb8(image)).unwrap();
+
+        assert_eq!(result.shifts.len(), 1);
+        assert_eq!((result.shifts[0].dx, result.shifts[0].dy), (60, 48));
+
+        // Both the source and the destination are marked, and nothing else
+        for (x, y, pixel) in result.mask.enumerate_pixels() {
+            let source = (10..42).contains(&x) && (12..44).contains(&y);
+            let destination = (70..102).contains(&x) && (60..92).contains(&y);
+            assert_eq!(pixel.0[0] == 255, source || destination, "pixel {x},{y}");
+        }
+    }
+
+    /// Smooth shading with some grain, like a photo, saved at quality 90, with the square from
+    /// 64 to 128 in both directions pasted from a copy that was saved at quality 60. The square
+    /// is on the 8 pixel JPEG grid, so crops of it recompress the same way as the whole image.
+    fn spliced() -> DynamicImage {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(4);
+        let original = DynamicImage::ImageRgb8(RgbImage::from_fn(192, 192, |x, y| {
+            let shade = 128.0 + 60.0 * (x as f32 / 9.0).sin() * (y as f32 / 13.0).cos();
+            let grain: f32 = rng.gen_range(-12.0..12.0);
+            let value = (shade + grain).clamp(0.0, 255.0) as u8;
+            Rgb([value, value / 2 + 40, 255 - value])
+        }));
+
+        let mut spliced = recompress(&original, 90);
+        let low_quality = recompress(&original, 60);
+        spliced.copy_from(&imageops::crop_imm(&low_quality, 64, 64, 64, 64).to_image(), 64, 64).unwrap();
+        DynamicImage::ImageRgb8(spliced)
+    }
+
+    /// Mean of the pixels inside and outside the pasted square, keeping a margin around its edge
+    /// that the blur in `jpeg_ghost` spreads over.
+    fn means(image: &GrayImage) -> (f32, f32) {
+        let (mut inside_sum, mut inside_count, mut outside_sum, mut outside_count) = (0.0, 0, 0.0, 0);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if (76..116).contains(&x) && (76..116).contains(&y) {
+                inside_sum += pixel.0[0] as f32;
+                inside_count += 1;
+            } else if !(52..140).contains(&x) || !(52..140).contains(&y) {
+                outside_sum += pixel.0[0] as f32;
+                outside_count += 1;
+            }
+        }
+        (inside_sum / inside_count as f32, outside_sum / outside_count as f32)
+    }
+
+    /// Whether the mean difference at `quality` is lower than at the qualities on either side.
+    fn dips_at(image: &DynamicImage, quality: u8) -> bool {
+        let i = GHOST_QUALITIES.iter().position(|q| *q == quality).unwrap();
+        let mean = |i: usize| jpeg_ghost(image, GHOST_QUALITIES[i]).1;
+        mean(i) < mean(i - 1) && mean(i) < mean(i + 1)
+    }
+
+    #[test]
+    fn jpeg_ghost_dips_at_the_quality_a_region_was_saved_at() {
+        let image = spliced();
+        let region = image.crop_imm(64, 64, 64, 64);
+        let rest = image.crop_imm(0, 0, 192, 64);
+
+        assert!(dips_at(&region, 60));
+        assert!(!dips_at(&rest, 60));
+        assert!(dips_at(&rest, 90));
+
+        // At quality 60 the pasted square barely changes while the rest of the image does
+        let (ghost, _) = jpeg_ghost(&image, 60);
+        let (inside, outside) = means(&ghost);
+        assert!(inside < 20.0 && outside > 100.0, "inside {inside}, outside {outside}");
+    }
+
+    #[test]
+    fn error_level_singles_out_the_pasted_region() {
+        // Recompressing at the pasted region's quality leaves it dark against the rest
+        let result = error_level(&spliced(), 60);
+        let (inside, outside) = means(&DynamicImage::ImageRgb8(result.heatmap).to_luma8());
+
+        assert!(result.max_difference > 0);
+        assert!(inside < 5.0 && outside > 20.0, "inside {inside}, outside {outside}");
+    }
+
+    #[test]
+    fn copy_move_rejects_images_smaller_than_a_block() {
+        for (width, height) in [(7, 100), (100, 7), (1, 1)] {
+            assert!(copy_move(&DynamicImage::ImageRgb8(noise(width, height, 2))).is_err());
+        }
+
+        let result = copy_move(&DynamicImage::ImageRgb8(noise(8, 8, 3))).unwrap();
+        assert!(result.shifts.is_empty());
+    }
+}
@@ -0,0 +1,508 @@
+use std::f32::consts::PI;
+
+use image::{Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::convolution::{self, Border, Kernel};
+use crate::image_f32::ImageF32;
+
+// -------------------------------------------------------------------------------------------------
+// Lines
+// -------------------------------------------------------------------------------------------------
+//
+// Every edge pixel votes for each line through it, written as `x cos(theta) + y sin(theta) = rho`
+// with `theta` the angle of the line's normal, and lines that many pixels agree on stand out as
+// peaks. The probabilistic variant (Matas, Galambos and Kittler, "Robust Detection of Lines Using
+// the Progressive Probabilistic Hough Transform") adds pixels in a random order and stops as soon
+// as a line has enough votes, then follows it through the edges to find where it starts and ends
+// and takes its pixels back out so they don't vote again.
+
+/// Number of angles lines are searched at, spread evenly over half a turn, i.e. 1 degree apart.
+const ANGLES: usize = 180;
+
+/// Seed for the order the probabilistic transform visits pixels in, so runs are repeatable.
+const PROBABILISTIC_SEED: u64 = 0x5EED;
+
+/// A line across the whole image.
+#[derive(Debug, Serialize)]
+pub struct Line {
+    /// Distance from the top left corner to the closest point on the line
+    pub rho: f32,
+    /// Angle of the line's normal from the x axis, in degrees from 0 to 180
+    pub theta: f32,
+    /// Number of edge pixels on the line
+    pub votes: usize,
+    /// Where the line enters and leaves the image
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+}
+
+/// A line segment found by the probabilistic transform.
+#[derive(Debug, Serialize)]
+pub struct Segment {
+    pub start: [usize; 2],
+    pub end: [usize; 2],
+    pub length: f32,
+    /// Number of edge pixels on the segment
+    pub votes: usize,
+}
+
+/// Cosine and sine of every angle searched.
+fn angles() -> Vec<(f32, f32)> {
+    (0..ANGLES).map(|i| {
+        let theta = i as f32 * PI / ANGLES as f32;
+        (theta.cos(), theta.sin())
+    }).collect()
+}
+
+/// Positions of the pixels set in an edge map.
+fn edge_points(edges: &ImageF32) -> Vec<(usize, usize)> {
+    (0..edges.height())
+        .flat_map(|y| (0..edges.width()).map(move |x| (x, y)))
+        .filter(|point| edges[*point] > 0.5)
+        .collect()
+}
+
+/// Accumulator with a row of distances for every angle. Distances can be negative, so they are
+/// offset by the largest possible distance.
+struct Accumulator {
+    votes: Vec<usize>,
+    trig: Vec<(f32, f32)>,
+    max_rho: usize,
+    rhos: usize,
+}
+
+impl Accumulator {
+    fn new(width: usize, height: usize) -> Accumulator {
+        let max_rho = ((width * width + height * height) as f32).sqrt().ceil() as usize;
+        let rhos = 2 * max_rho + 1;
+        Accumulator { votes: vec![0; ANGLES * rhos], trig: angles(), max_rho, rhos }
+    }
+
+    /// Column of the line at `angle` through a point.
+    fn rho_index(&self, (x, y): (usize, usize), angle: usize) -> usize {
+        let (cos, sin) = self.trig[angle];
+        ((x as f32 * cos + y as f32 * sin).round() as isize + self.max_rho as isize) as usize
+    }
+
+    /// Add (or with `-1`, take back) a point's vote for every line through it. Returns the angle
+    /// of the line with the most votes afterwards and its votes.
+    fn vote(&mut self, point: (usize, usize), change: isize) -> (usize, usize) {
+        let mut best = (0, 0);
+        for angle in 0..ANGLES {
+            let index = angle * self.rhos + self.rho_index(point, angle);
+            self.votes[index] = (self.votes[index] as isize + change) as usize;
+            if self.votes[index] > best.1 {
+                best = (angle, self.votes[index]);
+            }
+        }
+        best
+    }
+
+    /// Votes at an angle and distance, carrying on past the last angle into the first with the
+    /// distance flipped, since they are the same lines.
+    fn get(&self, angle: isize, rho: isize) -> Option<(usize, usize)> {
+        let (angle, rho) = if angle < 0 {
+            (angle + ANGLES as isize, self.rhos as isize - 1 - rho)
+        } else if angle >= ANGLES as isize {
+            (angle - ANGLES as isize, self.rhos as isize - 1 - rho)
+        } else {
+            (angle, rho)
+        };
+
+        if !(0..self.rhos as isize).contains(&rho) {
+            return None;
+        }
+        let index = angle as usize * self.rhos + rho as usize;
+        Some((self.votes[index], index))
+    }
+}
+
+/// Where the line `x cos + y sin = rho` crosses the edges of the image, if it does.
+fn clip(rho: f32, (cos, sin): (f32, f32), width: usize, height: usize) -> Option<([f32; 2], [f32; 2])> {
+    let (right, bottom) = ((width - 1) as f32, (height - 1) as f32);
+    let mut crossings: Vec<[f32; 2]> = Vec::new();
+
+    if sin.abs() > 1e-6 {
+        for x in [0.0, right] {
+            let y = (rho - x * cos) / sin;
+            if (0.0..=bottom).contains(&y) {
+                crossings.push([x, y]);
+            }
+        }
+    }
+    if cos.abs() > 1e-6 {
+        for y in [0.0, bottom] {
+            let x = (rho - y * sin) / cos;
+            if (0.0..=right).contains(&x) {
+                crossings.push([x, y]);
+            }
+        }
+    }
+
+    // A line through a corner crosses two edges at once, so take the two crossings furthest apart
+    let distance = |a: &[f32; 2], b: &[f32; 2]| (a[0] - b[0]).hypot(a[1] - b[1]);
+    let mut best: Option<([f32; 2], [f32; 2])> = None;
+    for (i, a) in crossings.iter().enumerate() {
+        for b in crossings[i + 1..].iter() {
+            if best.is_none_or(|(start, end)| distance(a, b) > distance(&start, &end)) {
+                best = Some((*a, *b));
+            }
+        }
+    }
+
+    best
+}
+
+/// Every line at least `min_votes` edge pixels lie on, strongest first.
+pub fn lines(edges: &ImageF32, min_votes: usize) -> Vec<Line> {
+    let (width, height) = (edges.width(), edges.height());
+    let points = edge_points(edges);
+    let mut accumulator = Accumulator::new(width, height);
+
+    // Each angle's row only depends on that angle, so the rows are filled in parallel
+    let (trig, max_rho, rhos) = (accumulator.trig.clone(), accumulator.max_rho as isize, accumulator.rhos);
+    accumulator.votes.par_chunks_mut(rhos).zip(trig.par_iter()).for_each(|(row, (cos, sin))| {
+        for (x, y) in points.iter() {
+            row[((*x as f32 * cos + *y as f32 * sin).round() as isize + max_rho) as usize] += 1;
+        }
+    });
+
+    // Keep peaks, breaking ties between equal neighbours by position so only one of them is kept
+    let mut peaks: Vec<(usize, usize, usize)> = Vec::new();
+    for angle in 0..ANGLES as isize {
+        for rho in 0..rhos as isize {
+            let (votes, index) = accumulator.get(angle, rho).unwrap();
+            if votes < min_votes {
+                continue;
+            }
+
+            let is_peak = (-1..=1).all(|d_angle| (-1..=1).all(|d_rho| {
+                match accumulator.get(angle + d_angle, rho + d_rho) {
+                    Some((other, other_index)) if other_index != index => votes > other || (votes == other && index < other_index),
+                    _ => true,
+                }
+            }));
+            if is_peak {
+                peaks.push((votes, angle as usize, rho as usize));
+            }
+        }
+    }
+    peaks.sort_by_key(|(votes, _, _)| std::cmp::Reverse(*votes));
+
+    peaks.into_iter().filter_map(|(votes, angle, rho_index)| {
+        let rho = rho_index as f32 - accumulator.max_rho as f32;
+        let (start, end) = clip(rho, accumulator.trig[angle], width, height)?;
+        Some(Line { rho, theta: angle as f32 * 180.0 / ANGLES as f32, votes, start, end })
+    }).collect()
+}
+
+/// Pixels along a line from `start`, one step at a time along whichever axis it moves most in,
+/// until it leaves the image.
+fn walk(start: (usize, usize), direction: (f32, f32), width: usize, height: usize) -> impl Iterator<Item = (usize, usize)> {
+    let scale = direction.0.abs().max(direction.1.abs());
+    let step = (direction.0 / scale, direction.1 / scale);
+
+    (0..).map(move |i| {
+        let x = (start.0 as f32 + i as f32 * step.0).round();
+        let y = (start.1 as f32 + i as f32 * step.1).round();
+        (x, y)
+    })
+    .take_while(move |(x, y)| *x >= 0.0 && *y >= 0.0 && *x < width as f32 && *y < height as f32)
+    .map(|(x, y)| (x as usize, y as usize))
+}
+
+/// Line segments at least `min_length` long that `min_votes` edge pixels have voted for, with gaps
+/// of up to `max_gap` missing pixels along them.
+pub fn segments(edges: &ImageF32, min_votes: usize, min_length: f32, max_gap: usize) -> Vec<Segment> {
+    let (width, height) = (edges.width(), edges.height());
+    let mut points = edge_points(edges);
+    points.shuffle(&mut StdRng::seed_from_u64(PROBABILISTIC_SEED));
+
+    // Edge pixels that aren't part of a segment yet, and those whose votes are in the accumulator
+    let mut remaining = vec![false; width * height];
+    let mut voted = vec![false; width * height];
+    for (x, y) in points.iter() {
+        remaining[y * width + x] = true;
+    }
+
+    let mut accumulator = Accumulator::new(width, height);
+    let mut segments = Vec::new();
+
+    for point in points {
+        if !remaining[point.1 * width + point.0] {
+            continue;
+        }
+
+        let (angle, votes) = accumulator.vote(point, 1);
+        voted[point.1 * width + point.0] = true;
+        if votes < min_votes {
+            continue;
+        }
+
+        // Follow the line both ways until the gap since the last edge pixel gets too long
+        let (cos, sin) = accumulator.trig[angle];
+        let ends: Vec<(usize, usize)> = [(-sin, cos), (sin, -cos)].iter().map(|direction| {
+            let mut end = point;
+            let mut gap = 0;
+            for pixel in walk(point, *direction, width, height) {
+                if remaining[pixel.1 * width + pixel.0] {
+                    end = pixel;
+                    gap = 0;
+                } else {
+                    gap += 1;
+                    if gap > max_gap {
+                        break;
+                    }
+                }
+            }
+            end
+        }).collect();
+
+        let length = (ends[0].0 as f32 - ends[1].0 as f32).hypot(ends[0].1 as f32 - ends[1].1 as f32);
+        let long_enough = length >= min_length;
+
+        // Take the pixels along the segment out so they aren't found again. Only a segment that is
+        // kept takes its votes back, so short ones can still add to the lines they cross.
+        let direction = (ends[1].0 as f32 - ends[0].0 as f32, ends[1].1 as f32 - ends[0].1 as f32);
+        let mut on_segment = 0;
+        let pixels: Vec<(usize, usize)> = if length > 0.0 {
+            let steps = direction.0.abs().max(direction.1.abs()) as usize;
+            walk(ends[0], direction, width, height).take(steps + 1).collect()
+        } else {
+            vec![point]
+        };
+
+        for pixel in pixels {
+            let index = pixel.1 * width + pixel.0;
+            if !remaining[index] {
+                continue;
+            }
+
+            remaining[index] = false;
+            on_segment += 1;
+            if long_enough && voted[index] {
+                accumulator.vote(pixel, -1);
+                voted[index] = false;
+            }
+        }
+
+        if long_enough {
+            segments.push(Segment { start: [ends[0].0, ends[0].1], end: [ends[1].0, ends[1].1], length, votes: on_segment });
+        }
+    }
+
+    segments
+}
+
+// -------------------------------------------------------------------------------------------------
+// Circles
+// -------------------------------------------------------------------------------------------------
+//
+// The gradient at an edge pixel on a circle points at or away from its centre, so rather than
+// voting for every circle through a pixel, each only votes for centres along its gradient. Peaks
+// in those votes are candidate centres, and the radius is whichever distance most edge pixels
+// around the centre agree on.
+
+/// Candidate centres checked for a radius, at most, taking the most voted for first.
+const MAX_CANDIDATES: usize = 1000;
+
+/// How closely an edge pixel's gradient needs to line up with the direction to the centre, as
+/// the cosine of the angle between them, for the pixel to count as being on the circle. Without
+/// this, busy texture covers the circumference of any circle drawn over it.
+const MIN_ALIGNMENT: f32 = 0.9;
+
+#[derive(Debug, Serialize)]
+pub struct Circle {
+    pub x: usize,
+    pub y: usize,
+    pub radius: usize,
+    /// Number of edge pixels on the circle
+    pub votes: usize,
+}
+
+/// Circles with a radius from `min_radius` to `max_radius` whose edge pixels cover at least
+/// `coverage` of their circumference, strongest first. `theta` is the gradient direction at each
+/// pixel.
+pub fn circles(edges: &ImageF32, theta: &ImageF32, min_radius: usize, max_radius: usize, coverage: f32) -> Vec<Circle> {
+    let (width, height) = (edges.width(), edges.height());
+    let points = edge_points(edges);
+    let min_radius = min_radius.max(1);
+
+    let mut centres = ImageF32::new(width, height);
+    for (x, y) in points.iter() {
+        let (sin, cos) = theta[(*x, *y)].sin_cos();
+
+        for radius in min_radius..=max_radius {
+            for sign in [-1.0, 1.0] {
+                let centre_x = (*x as f32 + sign * radius as f32 * cos).round();
+                let centre_y = (*y as f32 + sign * radius as f32 * sin).round();
+                if centre_x >= 0.0 && centre_y >= 0.0 && centre_x < width as f32 && centre_y < height as f32 {
+                    centres[(centre_x as usize, centre_y as usize)] += 1.0;
+                }
+            }
+        }
+    }
+
+    // Gradient directions are slightly off, which spreads the votes for a centre around it
+    let centres = convolution::convolve(&centres, &Kernel::gaussian(1.0, 2), Border::Zero);
+
+    // Even the smallest circle puts at least this many votes on its centre
+    let min_centre_votes = coverage * 2.0 * PI * min_radius as f32 * 0.25;
+    let mut candidates: Vec<(usize, usize, f32)> = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let votes = centres[(x, y)];
+            if votes < min_centre_votes {
+                continue;
+            }
+
+            let is_peak = (y.saturating_sub(1)..(y + 2).min(height)).all(|ny| {
+                (x.saturating_sub(1)..(x + 2).min(width)).all(|nx| {
+                    let other = centres[(nx, ny)];
+                    (nx, ny) == (x, y) || votes > other || (votes == other && (y, x) < (ny, nx))
+                })
+            });
+            if is_peak {
+                candidates.push((x, y, votes));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+    candidates.truncate(MAX_CANDIDATES);
+
+    // The radius most edge pixels around each centre agree on, counting a pixel either side of
+    // it since edges aren't exactly on the circle
+    let mut found: Vec<Circle> = candidates.par_iter().filter_map(|(x, y, _)| {
+        let mut counts = vec![0usize; max_radius + 2];
+        for (point_x, point_y) in points.iter() {
+            let (dx, dy) = (*point_x as f32 - *x as f32, *point_y as f32 - *y as f32);
+            let distance = dx.hypot(dy);
+            if distance.round() as usize >= counts.len() || distance == 0.0 {
+                continue;
+            }
+
+            let (sin, cos) = theta[(*point_x, *point_y)].sin_cos();
+            if ((dx * cos + dy * sin) / distance).abs() < MIN_ALIGNMENT {
+                continue;
+            }
+            counts[distance.round() as usize] += 1;
+        }
+
+        let (radius, votes) = (min_radius..=max_radius)
+            .map(|radius| (radius, counts[radius - 1] + counts[radius] + counts[radius + 1]))
+            .max_by_key(|(radius, votes)| (*votes, std::cmp::Reverse(*radius)))?;
+
+        if (votes as f32) < coverage * 2.0 * PI * radius as f32 {
+            return None;
+        }
+        Some(Circle { x: *x, y: *y, radius, votes })
+    }).collect();
+
+    // Drop circles centred within the smaller one's radius of a stronger circle, which are the
+    // same circle found again from a slightly different centre
+    found.sort_by(|a, b| {
+        let score = |circle: &Circle| circle.votes as f32 / circle.radius as f32;
+        score(b).total_cmp(&score(a))
+    });
+
+    let mut circles: Vec<Circle> = Vec::new();
+    for circle in found {
+        let duplicate = circles.iter().any(|kept| {
+            let distance = (kept.x as f32 - circle.x as f32).hypot(kept.y as f32 - circle.y as f32);
+            distance < kept.radius.min(circle.radius) as f32
+        });
+        if !duplicate {
+            circles.push(circle);
+        }
+    }
+
+    circles
+}
+
+// -------------------------------------------------------------------------------------------------
+// Output
+// -------------------------------------------------------------------------------------------------
+
+/// Colour that shapes are drawn in.
+pub const DRAW_COLOR: Rgb<u8> = Rgb([0, 255, 0]);
+
+pub fn format_json<T: Serialize>(shapes: &[T]) -> String {
+    serde_json::to_string_pretty(shapes).expect("Failed to serialize shapes")
+}
+
+fn put(image: &mut RgbImage, x: f32, y: f32, color: Rgb<u8>) {
+    let (x, y) = (x.round(), y.round());
+    if x >= 0.0 && y >= 0.0 && x < image.width() as f32 && y < image.height() as f32 {
+        image.put_pixel(x as u32, y as u32, color);
+    }
+}
+
+/// Draw a one pixel wide line, leaving out any part outside the image.
+pub fn draw_line(image: &mut RgbImage, start: [f32; 2], end: [f32; 2], color: Rgb<u8>) {
+    let steps = (end[0] - start[0]).abs().max((end[1] - start[1]).abs()).ceil().max(1.0);
+
+    for i in 0..=steps as usize {
+        let t = i as f32 / steps;
+        put(image, start[0] + t * (end[0] - start[0]), start[1] + t * (end[1] - start[1]), color);
+    }
+}
+
+/// Draw a one pixel wide circle, leaving out any part outside the image.
+pub fn draw_circle(image: &mut RgbImage, circle: &Circle, color: Rgb<u8>) {
+    // Enough steps that neighbouring points are less than a pixel apart
+    let steps = (4.0 * PI * circle.radius as f32).ceil().max(8.0) as usize;
+
+    for i in 0..steps {
+        let (sin, cos) = (i as f32 * 2.0 * PI / steps as f32).sin_cos();
+        put(image, circle.x as f32 + circle.radius as f32 * cos, circle.y as f32 + circle.radius as f32 * sin, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Edge map of a horizontal line at `y` from `start` to `end`.
+    fn horizontal(y: usize, start: usize, end: usize) -> ImageF32 {
+        ImageF32::from_fn(64, 64, |x, row| if row == y && (start..end).contains(&x) { 1.0 } else { 0.0 })
+    }
+
+    #[test]
+    fn lines_finds_a_single_line() {
+        let lines = lines(&horizontal(20, 4, 60), 30);
+
+        assert_eq!(lines.len(), 1);
+        assert_eq!((lines[0].rho, lines[0].theta, lines[0].votes), (20.0, 90.0, 56));
+    }
+
+    #[test]
+    fn segments_finds_the_ends() {
+        let segments = segments(&horizontal(20, 10, 51), 10, 10.0, 2);
+
+        assert_eq!(segments.len(), 1);
+        let mut ends = [segments[0].start, segments[0].end];
+        ends.sort();
+        assert_eq!(ends, [[10, 20], [50, 20]]);
+    }
+
+    #[test]
+    fn circles_finds_a_circle() {
+        let (centre_x, centre_y, radius) = (30.0f32, 34.0f32, 15.0f32);
+        let mut edges = ImageF32::new(64, 64);
+        for i in 0..360 {
+            let (sin, cos) = (i as f32).to_radians().sin_cos();
+            edges[((centre_x + radius * cos).round() as usize, (centre_y + radius * sin).round() as usize)] = 1.0;
+        }
+        let theta = ImageF32::from_fn(64, 64, |x, y| (y as f32 - centre_y).atan2(x as f32 - centre_x));
+
+        let circles = circles(&edges, &theta, 5, 25, 0.5);
+
+        assert_eq!(circles.len(), 1);
+        assert_eq!((circles[0].x, circles[0].y, circles[0].radius), (30, 34, 15));
+    }
+}
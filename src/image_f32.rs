@@ -0,0 +1,241 @@
+use std::ops::{Index, IndexMut};
+
+use image::{DynamicImage, GrayImage, Luma, RgbaImage};
+use rayon::prelude::*;
+
+/// A single channel float image stored as one contiguous row-major buffer. `stride` is the
+/// distance between the starts of two rows, which is the width for owned images but lets views
+/// borrow a window of a larger image without copying.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageF32 {
+    width: usize,
+    height: usize,
+    stride: usize,
+    data: Vec<f32>,
+}
+
+/// A borrowed rectangular window of an `ImageF32`.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageView<'a> {
+    width: usize,
+    height: usize,
+    stride: usize,
+    data: &'a [f32],
+}
+
+impl ImageF32 {
+    pub fn new(width: usize, height: usize) -> ImageF32 {
+        ImageF32::filled(width, height, 0.0)
+    }
+
+    pub fn filled(width: usize, height: usize, value: f32) -> ImageF32 {
+        ImageF32 { width, height, stride: width, data: vec![value; width * height] }
+    }
+
+    /// Wrap row-major data, so `data[y * width + x]` is the pixel at `(x, y)`.
+    pub fn from_vec(width: usize, height: usize, data: Vec<f32>) -> ImageF32 {
+        assert_eq!(data.len(), width * height, "Data does not match a {width}x{height} image");
+        ImageF32 { width, height, stride: width, data }
+    }
+
+    pub fn from_fn(width: usize, height: usize, f: impl Fn(usize, usize) -> f32 + Sync) -> ImageF32 {
+        let mut image = ImageF32::new(width, height);
+        image.par_rows_mut().for_each(|(y, row)| {
+            for (x, value) in row.iter_mut().enumerate() {
+                *value = f(x, y);
+            }
+        });
+        image
+    }
+
+    /// Rec. 709 luma of an image, from 0 to 1.
+    pub fn from_luma(image: &DynamicImage) -> ImageF32 {
+        let rgb = image.to_rgb32f();
+        let data = rgb.pixels()
+            .map(|pixel| (0.2126 * pixel.0[0]) + (0.7152 * pixel.0[1]) + (0.0722 * pixel.0[2]))
+            .collect();
+        ImageF32::from_vec(rgb.width() as usize, rgb.height() as usize, data)
+    }
+
+    /// A single channel of an RGBA image, from 0 to 255.
+    pub fn from_channel(rgba: &RgbaImage, channel: usize) -> ImageF32 {
+        let data = rgba.pixels().map(|pixel| pixel.0[channel] as f32).collect();
+        ImageF32::from_vec(rgba.width() as usize, rgba.height() as usize, data)
+    }
+
+    /// Convert values from 0 to 1 into a grayscale image, clamping anything outside and rounding
+    /// to the nearest level so that `from_luma` of a grayscale image converts back unchanged.
+    pub fn to_gray(&self) -> GrayImage {
+        let mut gray = GrayImage::new(self.width as u32, self.height as u32);
+        for (x, y, pixel) in gray.enumerate_pixels_mut() {
+            *pixel = Luma([(self[(x as usize, y as usize)].clamp(0.0, 1.0) * 255.0).round() as u8]);
+        }
+        gray
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn row(&self, y: usize) -> &[f32] {
+        &self.data[y * self.stride..y * self.stride + self.width]
+    }
+
+    /// Every row with its index, to be processed in parallel.
+    pub fn par_rows_mut(&mut self) -> impl IndexedParallelIterator<Item = (usize, &mut [f32])> {
+        let width = self.width;
+        self.data.par_chunks_mut(self.stride.max(1)).map(move |row| &mut row[..width]).enumerate()
+    }
+
+    /// Every pixel value, row by row.
+    pub fn values(&self) -> impl Iterator<Item = &f32> {
+        (0..self.height).flat_map(move |y| self.row(y).iter())
+    }
+
+    pub fn view(&self, x: usize, y: usize, width: usize, height: usize) -> ImageView<'_> {
+        assert!(x + width <= self.width && y + height <= self.height, "View is outside the image");
+
+        // An empty view can start past the end of the buffer, so it borrows nothing
+        if width == 0 || height == 0 {
+            return ImageView { width, height, stride: self.stride, data: &[] };
+        }
+
+        let start = y * self.stride + x;
+        let end = start + (height - 1) * self.stride + width;
+        ImageView { width, height, stride: self.stride, data: &self.data[start..end] }
+    }
+
+    pub fn as_view(&self) -> ImageView<'_> {
+        self.view(0, 0, self.width, self.height)
+    }
+
+    pub fn max(&self) -> f32 {
+        self.values().copied().fold(f32::NEG_INFINITY, f32::max)
+    }
+
+    /// Apply `f` to every pixel in place.
+    pub fn map_in_place(&mut self, f: impl Fn(f32) -> f32 + Sync) {
+        self.par_rows_mut().for_each(|(_, row)| {
+            for value in row {
+                *value = f(*value);
+            }
+        });
+    }
+}
+
+impl Index<(usize, usize)> for ImageF32 {
+    type Output = f32;
+
+    fn index(&self, (x, y): (usize, usize)) -> &f32 {
+        &self.data[y * self.stride + x]
+    }
+}
+
+impl IndexMut<(usize, usize)> for ImageF32 {
+    fn index_mut(&mut self, (x, y): (usize, usize)) -> &mut f32 {
+        &mut self.data[y * self.stride + x]
+    }
+}
+
+impl<'a> ImageView<'a> {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn row(&self, y: usize) -> &'a [f32] {
+        &self.data[y * self.stride..y * self.stride + self.width]
+    }
+}
+
+impl<'a> Index<(usize, usize)> for ImageView<'a> {
+    type Output = f32;
+
+    fn index(&self, (x, y): (usize, usize)) -> &f32 {
+        &self.data[y * self.stride + x]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gray_images_round_trip() {
+        let gray = GrayImage::from_fn(7, 5, |x, y| Luma([(x * 40 + y * 3) as u8]));
+        let image = ImageF32::from_luma(&DynamicImage::ImageLuma8(gray.clone()));
+
+        assert_eq!((image.width(), image.height()), (7, 5));
+        assert_eq!(image.to_gray(), gray);
+    }
+
+    #[test]
+    fn channels_keep_their_values() {
+        let rgba = RgbaImage::from_fn(3, 2, |x, y| image::Rgba([x as u8, y as u8, 200, 255 - x as u8]));
+
+        for channel in 0..4 {
+            let image = ImageF32::from_channel(&rgba, channel);
+            for (x, y, pixel) in rgba.enumerate_pixels() {
+                assert_eq!(image[(x as usize, y as usize)], pixel.0[channel] as f32);
+            }
+        }
+    }
+
+    #[test]
+    fn indexing_reaches_every_corner() {
+        let mut image = ImageF32::from_fn(6, 4, |x, y| (y * 10 + x) as f32);
+
+        assert_eq!(image[(0, 0)], 0.0);
+        assert_eq!(image[(5, 0)], 5.0);
+        assert_eq!(image[(0, 3)], 30.0);
+        assert_eq!(image[(5, 3)], 35.0);
+        assert_eq!(image.row(3), &[30.0, 31.0, 32.0, 33.0, 34.0, 35.0]);
+        assert_eq!(image.values().count(), 24);
+
+        image[(5, 3)] = -1.0;
+        assert_eq!(image.row(3)[5], -1.0);
+        assert_eq!(image.max(), 34.0);
+    }
+
+    #[test]
+    fn views_use_the_parent_stride() {
+        let image = ImageF32::from_fn(6, 4, |x, y| (y * 10 + x) as f32);
+
+        // A window in the bottom right corner ends on the last pixel of the buffer
+        let view = image.view(3, 2, 3, 2);
+        assert_eq!((view.width(), view.height()), (3, 2));
+        assert_eq!(view[(0, 0)], 23.0);
+        assert_eq!(view[(2, 1)], 35.0);
+        assert_eq!(view.row(0), &[23.0, 24.0, 25.0]);
+        assert_eq!(view.row(1), &[33.0, 34.0, 35.0]);
+
+        let column = image.view(5, 0, 1, 4);
+        assert_eq!((0..4).map(|y| column[(0, y)]).collect::<Vec<f32>>(), vec![5.0, 15.0, 25.0, 35.0]);
+
+        let whole = image.as_view();
+        assert_eq!(whole[(5, 3)], image[(5, 3)]);
+        assert_eq!(image.view(6, 4, 0, 0).height(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "View is outside the image")]
+    fn views_past_the_edge_panic() {
+        ImageF32::new(6, 4).view(4, 0, 3, 1);
+    }
+
+    #[test]
+    fn empty_images_have_no_rows() {
+        let mut image = ImageF32::new(0, 3);
+        image.map_in_place(|value| value + 1.0);
+
+        assert_eq!(image.values().count(), 0);
+        assert_eq!(image.par_rows_mut().count(), 0);
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serde::{Serialize, Deserialize};
+
+use crate::bktree::BkTree;
+use crate::dedupe;
+use crate::phash::{Hash, HashConfig};
+
+/// Hashes for a single file, keyed by `HashConfig::key`, along with when the file was last
+/// modified so that unchanged files don't need hashing again. Hashes are stored as written by
+/// `Hash::to_hex`, which keeps their length.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Entry {
+    pub modified: u64,
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// An on-disk database of image hashes, keyed by absolute path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Index {
+    pub entries: BTreeMap<String, Entry>,
+}
+
+/// What changed during an update.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+/// Milliseconds since the epoch that a file was last modified.
+fn modified(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map(|time| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Index {
+    /// Load an index, or start a new one if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Index {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).expect("Failed to read index"),
+            Err(_) => Index::default()
+        }
+    }
+
+    /// Write the index to a temporary file then move it into place, so that an interrupted save
+    /// never leaves a corrupt index behind.
+    pub fn save(&self, path: &Path) {
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_string(self).expect("Failed to serialize index"))
+            .expect("Failed to write index");
+        fs::rename(&temporary, path).expect("Failed to replace index");
+    }
+
+    /// Bring the index up to date with a file or directory: hash new files, re-hash files that
+    /// have been modified since they were indexed and drop files that no longer exist.
+    pub fn update(&mut self, root: &Path, config: &HashConfig) -> Summary {
+        let key = config.key();
+        let mut summary = Summary::default();
+
+        let root = root.canonicalize().expect("File or directory not found!");
+        let files = if root.is_dir() { dedupe::walk(&root) } else { vec![root.clone()] };
+        let found: HashSet<String> = files.iter().map(|file| file.to_string_lossy().to_string()).collect();
+
+        // Forget files under the root that have gone
+        let before = self.entries.len();
+        self.entries.retain(|path, _| !Path::new(path).starts_with(&root) || found.contains(path));
+        summary.removed = before - self.entries.len();
+
+        let mut added: HashSet<PathBuf> = HashSet::new();
+        let mut stale: Vec<PathBuf> = Vec::new();
+        for file in files.iter() {
+            let path = file.to_string_lossy().to_string();
+            let modified = modified(file);
+
+            match self.entries.get_mut(&path) {
+                Some(entry) if entry.modified == modified && Index::stored(entry, &key, config.bits()).is_some() => {
+                    summary.unchanged += 1;
+                    continue;
+                }
+                Some(entry) if entry.modified != modified => {
+                    entry.hashes.clear();
+                    entry.modified = modified;
+                }
+                Some(_) => {}
+                None => {
+                    added.insert(file.clone());
+                }
+            }
+
+            stale.push(file.clone());
+        }
+
+        for (path, hash) in dedupe::hash_files(&stale, config).into_iter() {
+            if added.contains(&path) {
+                summary.added += 1;
+            } else {
+                summary.updated += 1;
+            }
+
+            let entry = self.entries.entry(path.to_string_lossy().to_string()).or_insert_with(|| Entry {
+                modified: modified(&path),
+                hashes: BTreeMap::new(),
+            });
+            entry.hashes.insert(key.clone(), hash.to_hex());
+        }
+
+        // Modified files that can no longer be opened as images don't belong in the index
+        self.entries.retain(|_, entry| !entry.hashes.is_empty());
+
+        summary
+    }
+
+    /// The stored hash for a configuration, if there is one with the expected number of bits.
+    /// Indexes written before hashes kept their length can hold hashes padded to a whole number
+    /// of hex digits, which are hashed again.
+    fn stored(entry: &Entry, key: &str, bits: usize) -> Option<Hash> {
+        entry.hashes.get(key)
+            .and_then(|hex| Hash::from_hex(hex))
+            .filter(|hash| hash.len() == bits)
+    }
+
+    /// The `k` indexed files closest to `hash`, nearest first. Files without a stored hash of the
+    /// same length are skipped.
+    pub fn nearest(&self, hash: &Hash, config: &HashConfig, k: usize) -> Vec<(u32, String)> {
+        let key = config.key();
+        let mut tree = BkTree::new();
+
+        for (path, entry) in self.entries.iter() {
+            if let Some(stored) = Index::stored(entry, &key, hash.len()) {
+                tree.insert(stored, path);
+            }
+        }
+
+        tree.nearest(hash, k).into_iter().map(|(distance, path)| (distance, path.to_string())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn config() -> HashConfig {
+        HashConfig::new("phash", 5, false).unwrap()
+    }
+
+    /// A directory of distinct images, removed when dropped.
+    struct Images {
+        root: PathBuf,
+    }
+
+    impl Images {
+        fn new(name: &str) -> Images {
+            let root = std::env::temp_dir().join(format!("acetic_{name}_{}", std::process::id()));
+            fs::create_dir_all(&root).unwrap();
+
+            for (i, name) in ["bars.png", "rings.png", "ramp.png"].iter().enumerate() {
+                let image = GrayImage::from_fn(64, 64, |x, y| Luma([match i {
+                    0 => if (x / 8) % 2 == 0 { 255 } else { 0 },
+                    1 => (((x as f32 - 32.0).hypot(y as f32 - 32.0) / 4.0).sin() * 127.0 + 128.0) as u8,
+                    _ => (x * 2 + y * 2) as u8,
+                }]));
+                image.save(root.join(name)).unwrap();
+            }
+
+            Images { root: root.canonicalize().unwrap() }
+        }
+
+        fn path(&self, name: &str) -> String {
+            self.root.join(name).to_string_lossy().to_string()
+        }
+    }
+
+    impl Drop for Images {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    #[test]
+    fn persisted_index_answers_queries() {
+        let images = Images::new("index");
+        let db = images.root.join("index.json");
+
+        let mut index = Index::default();
+        assert_eq!(index.update(&images.root, &config()).added, 3);
+        index.save(&db);
+
+        let mut reloaded = Index::load(&db);
+        let query = dedupe::hash_file(Path::new(&images.path("rings.png")), &config()).unwrap();
+        let matches = reloaded.nearest(&query, &config(), 2);
+
+        assert_eq!(matches.len(), 2);
+        assert_eq!(matches[0], (0, images.path("rings.png")));
+        assert_eq!(reloaded.update(&images.root, &config()).unchanged, 3);
+    }
+
+    #[test]
+    fn hashes_of_the_wrong_length_are_rehashed_and_never_compared() {
+        let images = Images::new("index_legacy");
+        let mut index = Index::default();
+        index.update(&images.root, &config());
+
+        // Written before the length was kept, a 25 bit hash read back as 28 bits
+        let legacy = index.entries.get_mut(&images.path("bars.png")).unwrap();
+        let hex = legacy.hashes[&config().key()].split_once(':').unwrap().1.to_string();
+        legacy.hashes.insert(config().key(), hex);
+
+        let query = dedupe::hash_file(Path::new(&images.path("bars.png")), &config()).unwrap();
+        assert!(index.nearest(&query, &config(), 3).iter().all(|(_, path)| *path != images.path("bars.png")));
+
+        let summary = index.update(&images.root, &config());
+        assert_eq!((summary.updated, summary.unchanged), (1, 2));
+        assert_eq!(index.nearest(&query, &config(), 1)[0], (0, images.path("bars.png")));
+    }
+}
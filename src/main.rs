@@ -1,12 +1,30 @@
+#![cfg_attr(feature = "simd", feature(portable_simd))]
+
 mod crypt;
+mod dct;
 mod phash;
 mod png;
 mod jpeg;
 mod edge_detection;
+mod convolution;
+mod image_f32;
+mod simd;
+mod stc;
+mod bitplane;
+mod scan;
+mod bktree;
+mod dedupe;
+mod index;
+mod crop_resistant;
+mod animation;
+mod forensics;
+mod filter;
+mod hough;
+mod steganalysis;
 
 use clap::Parser;
 use chrono;
-use image::{Luma, Rgb, Rgb32FImage, GrayImage};
+use image_f32::ImageF32;
 use std::env;
 use std::str;
 use std::string::{String};
@@ -22,40 +40,226 @@ struct Args {
     process: String,
     filepath: String,
     data: Option<String>,
+
+    /// Embedding mode for encode and decode (lsb, lsbm, stc)
+    #[arg(long, default_value = "lsb")]
+    mode: String,
+
+    /// Distortion function used by the stc mode (hill, uniform)
+    #[arg(long, default_value = "hill")]
+    cost: String,
+
+    /// For bitplane and forensics jpeg-ghost, save every image in a single grid instead of
+    /// separate files
+    #[arg(long)]
+    sheet: bool,
+
+    /// For decode, try every known layout and format instead of just acetic's own. Steghide
+    /// payloads are only detected, not extracted
+    #[arg(long)]
+    scan: bool,
+
+    /// Edge detector used by edge (canny, sobel, prewitt, scharr, roberts, log, dog)
+    #[arg(long, default_value = "canny")]
+    operator: String,
+
+    /// For edge, find edges between colours in this colour space (rgb, lab) as well as between
+    /// light and dark
+    #[arg(long)]
+    color: Option<String>,
+
+    /// For edge, also save the gradient direction as a colour image
+    #[arg(long)]
+    direction: bool,
+
+    /// For edge and filter, standard deviation of the Gaussian blur
+    #[arg(long, default_value_t = 1.0)]
+    sigma: f32,
+
+    /// For edge and filter, radius of the kernel. Defaults to three times the sigma for Gaussian
+    /// kernels and 1 for the box and median filters
+    #[arg(long)]
+    radius: Option<i32>,
+
+    /// For filter unsharp, how much of the detail removed by the blur is added back
+    #[arg(long, default_value_t = 1.0)]
+    amount: f32,
+
+    /// For convolve, scale the kernel so its weights sum to 1
+    #[arg(long)]
+    normalize: bool,
+
+    /// For convolve, added to every pixel after convolving, out of 255
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    bias: f32,
+
+    /// For convolve, convolve the luminance instead of each colour channel
+    #[arg(long)]
+    gray: bool,
+
+    /// For lines, use the probabilistic Hough transform to find line segments instead of whole
+    /// lines
+    #[arg(long)]
+    probabilistic: bool,
+
+    /// For lines, how many edge pixels need to be on a line for it to count, at least 1. Defaults
+    /// to a quarter of the smaller side of the image, or a tenth with --probabilistic
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    votes: Option<usize>,
+
+    /// For lines --probabilistic, the shortest segment to keep, in pixels
+    #[arg(long, default_value_t = 30.0)]
+    min_length: f32,
+
+    /// For lines --probabilistic, the longest run of missing edge pixels a segment can bridge
+    #[arg(long, default_value_t = 5)]
+    max_gap: usize,
+
+    /// For circles, the smallest radius searched for
+    #[arg(long, default_value_t = 10)]
+    min_radius: usize,
+
+    /// For circles, the largest radius searched for. Defaults to half the smaller side of the image
+    #[arg(long)]
+    max_radius: Option<usize>,
+
+    /// For circles, the fraction of the circumference that needs to be on an edge
+    #[arg(long, default_value_t = 0.5)]
+    coverage: f32,
+
+    /// For lines and circles, save the shapes drawn over the image
+    #[arg(long)]
+    draw: bool,
+
+    /// For filter bilateral, how far apart two values can be, out of 255, and still be averaged
+    #[arg(long, default_value_t = 25.0)]
+    range_sigma: f32,
+
+    /// For edge, gradients below this fraction of the strongest are never edges
+    #[arg(long, default_value_t = 0.05)]
+    low: f32,
+
+    /// For edge, gradients above this fraction of the strongest are always edges
+    #[arg(long, default_value_t = 0.75)]
+    high: f32,
+
+    /// For edge, how gradients are combined into a magnitude (l1, l2)
+    #[arg(long, default_value = "l2")]
+    norm: String,
+
+    /// For edge, filter and convolve, what kernels see past the edge of the image (zero, replicate,
+    /// reflect, wrap)
+    #[arg(long, default_value = "replicate")]
+    border: String,
+
+    /// For edge, pick the thresholds from the image with Otsu's method instead of --low and --high
+    #[arg(long)]
+    auto_threshold: bool,
+
+    /// Hashing algorithm used by phash (phash, ahash, dhash, whash, blockmean, colormoment)
+    #[arg(long, default_value = "phash")]
+    algo: String,
+
+    /// Width and height of the grid of bits hashed by phash, e.g. 8 for a 64 bit hash
+    #[arg(long, default_value_t = 8)]
+    hash_size: u32,
+
+    /// Hash every rotation and flip of each image and keep the smallest, so that rotated and
+    /// mirrored copies match
+    #[arg(long)]
+    dihedral: bool,
+
+    /// For phash and compare, hash each region of the image separately so that cropped copies
+    /// still match
+    #[arg(long)]
+    crop_resistant: bool,
+
+    /// For compare --crop-resistant, how many regions need to match for the images to be the same
+    #[arg(long, default_value_t = 2)]
+    regions: usize,
+
+    /// For phash and compare, hash every keyframe of an animated GIF or APNG into a sequence
+    #[arg(long)]
+    animated: bool,
+
+    /// For compare --animated, the fraction of the shorter sequence that needs to match in order
+    /// for the animations to be the same
+    #[arg(long, default_value_t = 0.8)]
+    frame_match: f32,
+
+    /// For forensics ela, the JPEG quality the image is recompressed at
+    #[arg(long, default_value_t = 90)]
+    quality: u8,
+
+    /// For phash, print the hash as base64 instead of hex
+    #[arg(long)]
+    base64: bool,
+
+    /// For compare and dedupe, the largest Hamming distance at which two images count as the same
+    #[arg(long, default_value_t = 10)]
+    threshold: u32,
+
+    /// Output format for dedupe (text, json)
+    #[arg(long, default_value = "text")]
+    format: String,
+
+    /// For dedupe, what to do with all but the largest file in each cluster (move, delete)
+    #[arg(long)]
+    action: Option<String>,
+
+    /// For dedupe --action move, the directory to move duplicates into
+    #[arg(long)]
+    dest: Option<String>,
+
+    /// Hash database used by index and query
+    #[arg(long, default_value = "acetic_index.json")]
+    db: String,
+
+    /// Number of threads to process with. Defaults to the number of cores
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// For query, how many of the closest matches to return
+    #[arg(short, default_value_t = 5)]
+    k: usize,
 }
 
-fn edge(path: String) -> String {
+/// Build the path for a processed image, saved next to the original as
+/// `{UTC NOW}_{prefix}_{ORIGINAL FILE NAME}`. The prefix is left out if empty.
+fn output_path(path: &str, prefix: &str) -> String {
     let mut out_path  = path.split("/").collect::<Vec<&str>>();
     let file_name = out_path.pop().unwrap();
-    let output_name = format!("{}/{}_{}", out_path.join("/"), chrono::offset::Utc::now(), file_name);
-
-    let img = png::create(path);
 
-    // All operations use a 2D Vec of F32 and image `.to_vec()` outputs a 1D Vec so instead we
-    // create an intermediary image instance in RGB F32 and add those values to `new_img`.
-    let mut new_img: Vec<Vec<f32>> = vec![vec![0.0;img.height as usize];img.width as usize];
-    let temp_image = img.image.into_rgb32f();
+    if prefix.is_empty() {
+        format!("{}/{}_{}", out_path.join("/"), chrono::offset::Utc::now(), file_name)
+    } else {
+        format!("{}/{}_{}_{}", out_path.join("/"), chrono::offset::Utc::now(), prefix, file_name)
+    }
+}
 
-    let stop_x = img.width as usize;
-    let stop_y = img.height as usize;
+/// Like `output_path` but always saves as PNG, for outputs that need to be pixel exact.
+fn lossless_output_path(path: &str, prefix: &str) -> String {
+    let output_name = output_path(path, prefix);
 
-    for x in 0..stop_x {
-        for y in 0..stop_y {
-            let gray = (0.2126 * temp_image.get_pixel(x as u32, y as u32)[0]) + (0.7152 * temp_image.get_pixel(x as u32, y as u32)[1]) + (0.0722 * temp_image.get_pixel(x as u32, y as u32)[2]);
-            new_img[x][y] = gray;
-        }
+    if output_name.ends_with(".png") {
+        output_name
+    } else {
+        format!("{}.png", output_name)
     }
+}
 
-    let edge = edge_detection::detect(new_img);
-    let mut altered_image = GrayImage::new(img.width, img.height);
+fn edge(path: String, operator: edge_detection::Operator, params: &edge_detection::CannyParams, color: Option<edge_detection::ColorSpace>, direction: bool) -> String {
+    let output_name = output_path(&path, "");
 
-    for x in 0..stop_x {
-        for y in 0..stop_y {
-            let pix_arr = edge[x][y].clone();
-            let data = [(pix_arr * 255.0) as u8];
-            altered_image.put_pixel(x as u32, y as u32, Luma(data));
-        }
-    }
+    let img = png::create(path.clone());
+    let channels = match color {
+        Some(space) => edge_detection::color_channels(&img.image, space),
+        None => vec![ImageF32::from_luma(&img.image)],
+    };
+    let altered_image = match edge_detection::detect_with(&channels, operator, params) {
+        Ok(edges) => edges.to_gray(),
+        Err(error) => return error,
+    };
 
     println!("Saving file to {output_name}");
 
@@ -64,15 +268,244 @@ fn edge(path: String) -> String {
         Err(e) => println!("{e}")
     };
 
+    if direction {
+        match edge_detection::gradients(&channels, operator, params) {
+            Some((magnitude, theta)) => {
+                let direction_name = output_path(&path, "direction");
+                println!("Saving gradient direction to {direction_name}");
+                edge_detection::direction_image(&magnitude, &theta).save(&direction_name).expect("Failed to save direction image");
+            },
+            None => println!("{:?} doesn't measure a gradient, so has no direction to save", operator),
+        }
+    }
+
     return output_name
 }
 
-fn encode( path: String, data: Vec<u8> ) -> String {
+impl Args {
+    fn hash_config( &self ) -> Result<phash::HashConfig, String> {
+        return phash::HashConfig::new( &self.algo, self.hash_size, self.dihedral );
+    }
+
+    fn canny_params( &self ) -> edge_detection::CannyParams {
+        edge_detection::CannyParams {
+            sigma: self.sigma,
+            radius: self.radius.unwrap_or( edge_detection::CannyParams::radius_for( self.sigma ) ),
+            low: self.low,
+            high: self.high,
+            norm: edge_detection::GradientNorm::parse( &self.norm ),
+            auto_threshold: self.auto_threshold,
+            border: convolution::Border::parse( &self.border ),
+        }
+    }
+
+    /// The filter called `name` with its settings from the arguments, or `None` if there isn't one.
+    fn filter( &self, name: &str ) -> Option<filter::Filter> {
+        let radius = |default: i32| self.radius.unwrap_or( default ).max( 0 ) as usize;
+        let blur_radius = radius( edge_detection::CannyParams::radius_for( self.sigma ) );
+
+        return match name {
+            "gaussian" => Some( filter::Filter::Gaussian { sigma: self.sigma, radius: blur_radius } ),
+            "box" => Some( filter::Filter::Box { radius: radius( 1 ) } ),
+            "sharpen" => Some( filter::Filter::Sharpen ),
+            "unsharp" => Some( filter::Filter::Unsharp { sigma: self.sigma, radius: blur_radius, amount: self.amount } ),
+            "median" => Some( filter::Filter::Median { radius: radius( 1 ) } ),
+            "bilateral" => Some( filter::Filter::Bilateral { sigma: self.sigma, radius: blur_radius, range_sigma: self.range_sigma } ),
+            _ => None,
+        };
+    }
+}
+
+fn hash( path: String, config: &phash::HashConfig, base64: bool, crop_resistant: bool ) -> String {
+    let image = png::create( path ).image;
+
+    if crop_resistant {
+        return crop_resistant::hash( &image, config.hasher().as_ref() ).to_string();
+    }
+
+    let hash = config.hasher().hash( &image );
+
+    if base64 {
+        return hash.to_base64();
+    }
+    return hash.to_hex();
+}
+
+/// Hash an image, or parse the input as a hash if it isn't a file.
+fn load_hash( input: &str, config: &phash::HashConfig ) -> Result<phash::Hash, String> {
+    if std::path::Path::new( input ).is_file() {
+        let image = png::create( input.to_string() ).image;
+        return Ok( config.hasher().hash( &image ) );
+    }
+
+    return phash::Hash::parse( input ).ok_or_else( || format!( "{input} is neither an image nor a hash" ) );
+}
+
+/// Hash each region of an image, or parse the input as a list of hashes if it isn't a file.
+fn load_multi_hash( input: &str, config: &phash::HashConfig ) -> Result<crop_resistant::MultiHash, String> {
+    if std::path::Path::new( input ).is_file() {
+        let image = png::create( input.to_string() ).image;
+        return Ok( crop_resistant::hash( &image, config.hasher().as_ref() ) );
+    }
+
+    return crop_resistant::MultiHash::parse( input ).ok_or_else( || format!( "{input} is neither an image nor a list of hashes" ) );
+}
+
+fn compare_regions( first: String, second: String, config: &phash::HashConfig, threshold: u32, regions: usize ) -> String {
+    let ( first_hash, second_hash ) = match ( load_multi_hash( &first, config ), load_multi_hash( &second, config ) ) {
+        ( Ok( first_hash ), Ok( second_hash ) ) => ( first_hash, second_hash ),
+        ( Err( error ), _ ) | ( _, Err( error ) ) => return error,
+    };
+
+    println!( "{first}: {} regions", first_hash.hashes.len() );
+    println!( "{second}: {} regions", second_hash.hashes.len() );
+
+    let matching = first_hash.matching_regions( &second_hash, threshold );
+    let verdict = if matching >= regions { "Same" } else { "Different" };
+
+    return format!( "Matched {}/{} regions - {}", matching, first_hash.hashes.len(), verdict );
+}
+
+fn hash_animation( path: String, config: &phash::HashConfig ) -> String {
+    let frames = match animation::frames( std::path::Path::new( &path ) ) {
+        Ok( frames ) => frames,
+        Err( error ) => return format!( "Failed to open {path}: {error}" ),
+    };
+    let sequence = animation::hash( &frames, config.hasher().as_ref() );
+
+    println!( "Hashed {} frames into {} keyframes", frames.len(), sequence.frames.len() );
+    return sequence.to_string();
+}
+
+/// Hash the keyframes of an animation, or parse the input as a sequence of hashes if it isn't a
+/// file.
+fn load_sequence_hash( input: &str, config: &phash::HashConfig ) -> Result<animation::SequenceHash, String> {
+    let path = std::path::Path::new( input );
+    if path.is_file() {
+        let frames = animation::frames( path ).map_err( |error| format!( "Failed to open {input}: {error}" ) )?;
+        return Ok( animation::hash( &frames, config.hasher().as_ref() ) );
+    }
+
+    return animation::SequenceHash::parse( input ).ok_or_else( || format!( "{input} is neither an image nor a list of hashes" ) );
+}
+
+fn compare_sequences( first: String, second: String, config: &phash::HashConfig, threshold: u32, frame_match: f32 ) -> String {
+    let ( first_hash, second_hash ) = match ( load_sequence_hash( &first, config ), load_sequence_hash( &second, config ) ) {
+        ( Ok( first_hash ), Ok( second_hash ) ) => ( first_hash, second_hash ),
+        ( Err( error ), _ ) | ( _, Err( error ) ) => return error,
+    };
+
+    println!( "{first}: {} keyframes", first_hash.frames.len() );
+    println!( "{second}: {} keyframes", second_hash.frames.len() );
+
+    let matching = first_hash.matching_frames( &second_hash, threshold );
+    let similarity = first_hash.similarity( &second_hash, threshold );
+    let verdict = if similarity >= frame_match { "Same" } else { "Different" };
+
+    return format!( "Matched {} keyframes in order ({:.1}% of the shorter) - {}", matching, similarity * 100.0, verdict );
+}
+
+fn compare( first: String, second: String, config: &phash::HashConfig, threshold: u32 ) -> String {
+    let ( first_hash, second_hash ) = match ( load_hash( &first, config ), load_hash( &second, config ) ) {
+        ( Ok( first_hash ), Ok( second_hash ) ) => ( first_hash, second_hash ),
+        ( Err( error ), _ ) | ( _, Err( error ) ) => return error,
+    };
+
+    println!( "{first}: {first_hash}" );
+    println!( "{second}: {second_hash}" );
+
+    let ( distance, similarity ) = match ( first_hash.distance( &second_hash ), first_hash.similarity( &second_hash ) ) {
+        ( Some( distance ), Some( similarity ) ) => ( distance, similarity ),
+        _ => return format!( "Can't compare a {} bit hash with a {} bit hash", first_hash.len(), second_hash.len() ),
+    };
+    let verdict = if distance <= threshold { "Same" } else { "Different" };
+
+    return format!(
+        "Distance {}/{} ({:.1}% similar) - {}",
+        distance, first_hash.len(), similarity * 100.0, verdict
+    );
+}
+
+fn dedupe( path: String, args: &Args ) -> String {
+    let directory = std::path::Path::new( &path );
+    let files = dedupe::walk( directory );
+    println!( "Hashing {} images", files.len() );
+
+    let config = match args.hash_config() {
+        Ok( config ) => config,
+        Err( error ) => return error,
+    };
+    let hashes = dedupe::hash_files( &files, &config );
+    let clusters = dedupe::cluster( hashes, args.threshold );
+
+    match args.format.as_str() {
+        "json" => println!( "{}", dedupe::format_json( &clusters ) ),
+        _ => println!( "{}", dedupe::format_text( &clusters ) ),
+    }
+
+    match args.action.as_deref() {
+        Some( "delete" ) => dedupe::delete_duplicates( &clusters ),
+        Some( "move" ) => {
+            let destination = args.dest.clone().expect( "--action move needs a --dest directory. Aborting." );
+            dedupe::move_duplicates( &clusters, std::path::Path::new( &destination ) );
+        },
+        Some( action ) => println!( "Unknown action {action}. Expected one of: move, delete" ),
+        None => {}
+    }
+
+    let duplicates: usize = clusters.iter().map( |cluster| cluster.files.len() - 1 ).sum();
+    return format!( "Found {} clusters with {} duplicates", clusters.len(), duplicates );
+}
+
+fn index( path: String, args: &Args ) -> String {
+    let db = std::path::Path::new( &args.db );
+    let mut index = index::Index::load( db );
+
+    let config = match args.hash_config() {
+        Ok( config ) => config,
+        Err( error ) => return error,
+    };
+    let summary = index.update( std::path::Path::new( &path ), &config );
+    index.save( db );
+
+    return format!(
+        "Added {}, updated {}, removed {}, unchanged {} ({} files indexed)",
+        summary.added, summary.updated, summary.removed, summary.unchanged, index.entries.len()
+    );
+}
+
+fn query( path: String, args: &Args ) -> String {
+    let index = index::Index::load( std::path::Path::new( &args.db ) );
+    let config = match args.hash_config() {
+        Ok( config ) => config,
+        Err( error ) => return error,
+    };
+    let hash = match load_hash( &path, &config ) {
+        Ok( hash ) => hash,
+        Err( error ) => return error,
+    };
+    let matches = index.nearest( &hash, &config, args.k );
+
+    for ( distance, file ) in matches.iter() {
+        println!( "{:>4} {}", distance, file );
+    }
+
+    return format!( "Found {} matches", matches.len() );
+}
+
+fn encode( path: String, data: Vec<u8>, secret_key: &str, mode: &str, cost: &str ) -> String {
     let mut encoded: String = String::new();
     let ext = path.split( "." ).last().unwrap();
     if ext.contains( "png" ) {
         let image = png::create( path );
-        encoded = image.encode( data );
+        encoded = match mode {
+            "stc" => match image.encode_stc( data, stc::distortion( cost ).as_ref() ) {
+                Ok( enc_path ) => enc_path,
+                Err( error ) => return error,
+            },
+            "lsbm" => image.encode_matching( data, secret_key ),
+            _ => image.encode( data ),
+        };
     } else if ext.contains( ".jpg" ) {
         let image = jpeg::create( path );
         encoded = image.encode( data );
@@ -81,16 +514,255 @@ fn encode( path: String, data: Vec<u8> ) -> String {
     return encoded;
 }
 
-fn decode( path: String, secret_key: String ) -> String {
+fn scan( path: String, secret_key: String ) -> String {
+    let image = png::create( path );
+    let findings = scan::scan( &image.image.to_rgba8(), &secret_key );
+    let mut found = findings.len();
+
+    for finding in findings.iter() {
+        println!( "{finding}" );
+    }
+
+    // STC payloads are spread by the code rather than laid out in order, so check for them
+    // separately
+    let stc = image.decode_stc().unwrap_or_default();
+    if !stc.is_empty() {
+        match crypt::try_decrypt( secret_key, stc.as_slice() ) {
+            Some( plain_text ) => println!( "{:<16} {:<10} \"{}\"", "stc", "acetic", String::from_utf8_lossy( &plain_text ) ),
+            None => println!( "{:<16} {:<10} {} encrypted bytes, wrong password", "stc", "acetic", stc.len() ),
+        }
+        found += 1;
+    }
+
+    return format!( "Found {} possible payloads", found );
+}
+
+fn decode( path: String, secret_key: String, mode: &str ) -> String {
     let mut decoded: Vec::<u8> = Vec::new();
     if path.split( "." ).last().unwrap().contains( "png" ) {
         let image = png::create( path );
-        decoded = image.decode();
+        decoded = match mode {
+            "stc" => match image.decode_stc() {
+                Ok( data ) => data,
+                Err( error ) => return error,
+            },
+            _ => image.decode(),
+        };
     }
     let plain_text = crypt::decrypt( secret_key, decoded.as_slice());
     return String::from_utf8( plain_text ).unwrap();
 }
 
+fn analyze( path: String ) -> String {
+    let image = png::create( path.clone() );
+    let reports = steganalysis::analyze( &image );
+    println!( "{}", steganalysis::format_report( &reports ) );
+
+    let lsb_path = lossless_output_path( &path, "lsb" );
+
+    println!( "Saving LSB plane to {lsb_path}" );
+    steganalysis::lsb_plane( &image ).save( &lsb_path ).expect( "Failed to save LSB plane" );
+
+    let rate = reports.iter().map( |report| report.estimated_rate() ).fold( 0.0, f64::max );
+    return format!( "Estimated embedding rate: {:.4} bits per channel", rate );
+}
+
+fn bitplane( path: String, sheet: bool ) -> String {
+    let image = png::create( path.clone() ).image;
+
+    if sheet {
+        let output_name = lossless_output_path( &path, "bitplanes" );
+        println!( "Saving contact sheet to {output_name}" );
+        bitplane::contact_sheet( &image ).save( &output_name ).expect( "Failed to save contact sheet" );
+        return output_name;
+    }
+
+    let rgba = image.to_rgba8();
+    let mut saved = Vec::new();
+    for channel in 0..bitplane::channel_count( &image ) {
+        for bit in 0..8 {
+            let prefix = format!( "{}{}", bitplane::CHANNEL_NAMES[channel], bit );
+            let output_name = lossless_output_path( &path, &prefix );
+            println!( "Saving {prefix} to {output_name}" );
+            bitplane::plane( &rgba, channel, bit ).save( &output_name ).expect( "Failed to save bit plane" );
+            saved.push( output_name );
+        }
+    }
+
+    return format!( "Saved {} bit planes", saved.len() );
+}
+
+fn copy_move( path: String ) -> String {
+    let image = png::create( path.clone() ).image;
+    let result = match forensics::copy_move( &image ) {
+        Ok( result ) => result,
+        Err( error ) => return error,
+    };
+
+    for shift in result.shifts.iter() {
+        println!( "Shift ({}, {}) - {} matching blocks", shift.dx, shift.dy, shift.votes );
+    }
+
+    let mask_path = lossless_output_path( &path, "copymove" );
+    println!( "Saving mask to {mask_path}" );
+    result.mask.save( &mask_path ).expect( "Failed to save mask" );
+
+    let cloned = result.mask.pixels().filter( |pixel| pixel.0[0] > 0 ).count();
+    return format!( "Found {} cloned regions covering {} pixels", result.shifts.len(), cloned );
+}
+
+fn error_level( path: String, quality: u8 ) -> String {
+    let image = png::create( path.clone() ).image;
+    let result = forensics::error_level( &image, quality );
+
+    let output_name = output_path( &path, "ela" );
+    println!( "Saving heatmap to {output_name}" );
+    result.heatmap.save( &output_name ).expect( "Failed to save heatmap" );
+
+    return format!( "Largest difference at quality {}: {}", quality, result.max_difference );
+}
+
+fn jpeg_ghost( path: String, sheet: bool ) -> String {
+    let image = png::create( path.clone() ).image;
+    let mut ghosts = Vec::new();
+    let mut means = Vec::new();
+
+    for quality in forensics::GHOST_QUALITIES {
+        let ( ghost, mean ) = forensics::jpeg_ghost( &image, quality );
+        println!( "Quality {:>3}: mean squared difference {:.2}", quality, mean );
+
+        ghosts.push( ghost );
+        means.push( mean );
+    }
+
+    // The difference shrinks as the quality goes up, except for dips at qualities the image, or
+    // part of it, was saved at before
+    let dips: Vec<String> = ( 1..means.len() - 1 )
+        .filter( |i| means[*i] < means[i - 1] && means[*i] < means[i + 1] )
+        .map( |i| forensics::GHOST_QUALITIES[i].to_string() )
+        .collect();
+
+    if sheet {
+        let output_name = lossless_output_path( &path, "ghosts" );
+        println!( "Saving sheet to {output_name}" );
+        forensics::ghost_sheet( &ghosts ).save( &output_name ).expect( "Failed to save sheet" );
+    } else {
+        for ( quality, ghost ) in forensics::GHOST_QUALITIES.iter().zip( ghosts.iter() ) {
+            let output_name = lossless_output_path( &path, &format!( "ghost{quality}" ) );
+            println!( "Saving quality {quality} to {output_name}" );
+            ghost.save( &output_name ).expect( "Failed to save ghost" );
+        }
+    }
+
+    if dips.is_empty() {
+        return String::from( "No earlier qualities found" );
+    }
+    return format!( "Possibly saved before at quality {}", dips.join( ", " ) );
+}
+
+fn filter( path: String, filter: &filter::Filter, border: convolution::Border ) -> String {
+    let image = png::create( path.clone() ).image;
+    let filtered = filter::filter_image( &image, filter, border );
+
+    let output_name = output_path( &path, filter.name() );
+    println!( "Saving filtered image to {output_name}" );
+    filtered.save( &output_name ).expect( "Failed to save filtered image" );
+
+    return output_name;
+}
+
+/// Read a kernel from a text or JSON file, or parse the input as a kernel if it isn't a file.
+fn load_kernel( input: &str ) -> Result<convolution::Kernel, String> {
+    if std::path::Path::new( input ).is_file() {
+        let text = std::fs::read_to_string( input ).map_err( |error| format!( "Failed to read {input}: {error}" ) )?;
+        return convolution::Kernel::parse( &text );
+    }
+
+    return convolution::Kernel::parse( input );
+}
+
+fn convolve( path: String, kernel: &convolution::Kernel, bias: f32, gray: bool, border: convolution::Border ) -> String {
+    let image = png::create( path.clone() ).image;
+
+    let convolved = if gray {
+        let mut luma = convolution::convolve( &ImageF32::from_luma( &image ), kernel, border );
+        luma.map_in_place( |value| value + bias / 255.0 );
+        image::DynamicImage::ImageLuma8( luma.to_gray() )
+    } else {
+        filter::map_channels( &image, |channel| {
+            let mut convolved = convolution::convolve( channel, kernel, border );
+            convolved.map_in_place( |value| value + bias );
+            convolved
+        } )
+    };
+
+    let output_name = output_path( &path, "convolved" );
+    println!( "Saving {}x{} convolution to {output_name}", kernel.width(), kernel.height() );
+    convolved.save( &output_name ).expect( "Failed to save convolved image" );
+
+    return output_name;
+}
+
+fn lines( path: String, args: &Args ) -> String {
+    let image = png::create( path.clone() ).image;
+    let edges = edge_detection::detect( &[ImageF32::from_luma( &image )], &args.canny_params() );
+    let side = edges.width().min( edges.height() );
+    let mut drawing = image.to_rgb8();
+
+    let found = if args.probabilistic {
+        let segments = hough::segments( &edges, args.votes.unwrap_or( ( side / 10 ).max( 1 ) ), args.min_length, args.max_gap );
+        println!( "{}", hough::format_json( &segments ) );
+
+        for segment in segments.iter() {
+            let to_f32 = |point: [usize; 2]| [point[0] as f32, point[1] as f32];
+            hough::draw_line( &mut drawing, to_f32( segment.start ), to_f32( segment.end ), hough::DRAW_COLOR );
+        }
+        segments.len()
+    } else {
+        let lines = hough::lines( &edges, args.votes.unwrap_or( ( side / 4 ).max( 1 ) ) );
+        println!( "{}", hough::format_json( &lines ) );
+
+        for line in lines.iter() {
+            hough::draw_line( &mut drawing, line.start, line.end, hough::DRAW_COLOR );
+        }
+        lines.len()
+    };
+
+    if args.draw {
+        let output_name = output_path( &path, "lines" );
+        println!( "Saving lines to {output_name}" );
+        drawing.save( &output_name ).expect( "Failed to save lines" );
+    }
+
+    return format!( "Found {} lines", found );
+}
+
+fn circles( path: String, args: &Args ) -> String {
+    let image = png::create( path.clone() ).image;
+    let gray = [ImageF32::from_luma( &image )];
+    let params = args.canny_params();
+
+    let edges = edge_detection::detect( &gray, &params );
+    let ( _, theta ) = edge_detection::gradients( &gray, edge_detection::Operator::Canny, &params ).expect( "Canny measures a gradient" );
+    let max_radius = args.max_radius.unwrap_or( edges.width().min( edges.height() ) / 2 );
+
+    let circles = hough::circles( &edges, &theta, args.min_radius, max_radius, args.coverage );
+    println!( "{}", hough::format_json( &circles ) );
+
+    if args.draw {
+        let mut drawing = image.to_rgb8();
+        for circle in circles.iter() {
+            hough::draw_circle( &mut drawing, circle, hough::DRAW_COLOR );
+        }
+
+        let output_name = output_path( &path, "circles" );
+        println!( "Saving circles to {output_name}" );
+        drawing.save( &output_name ).expect( "Failed to save circles" );
+    }
+
+    return format!( "Found {} circles", circles.len() );
+}
+
 fn main() {
     let start = SystemTime::now();
 
@@ -98,7 +770,7 @@ fn main() {
 
     let args: Args = Args::parse();
     let path = args.filepath.clone();
-    let process = args.process;
+    let process = args.process.clone();
     let mut output = String::new();
     let mut encrypted= Vec::new();
     let mut data = String::new();
@@ -107,7 +779,7 @@ fn main() {
     let mut end_sequence: Vec<u8> = END.as_bytes().to_vec();
 
     if process.as_str() == "encode" {
-        data = args.data.expect( "No data found to encode. Aborting." );
+        data = args.data.clone().expect( "No data found to encode. Aborting." );
         if data.len() > 0 {
             encrypted = crypt::encrypt( secret_key.clone(), data );
         }
@@ -117,14 +789,66 @@ fn main() {
     start_sequence.append( &mut encrypted );
     start_sequence.append( &mut end_sequence );
 
-    // let default_parallelism_approx = available_parallelism().unwrap().get();
-    // println!("Available cores (approx.): {}", default_parallelism_approx);
+    let threads = args.threads.unwrap_or( available_parallelism().map( |n| n.get() ).unwrap_or( 1 ) );
+    rayon::ThreadPoolBuilder::new().num_threads( threads ).build_global().expect( "Failed to start thread pool" );
 
     match process.as_str() {
-        "edge" => output = edge( path ),
-        "phash" => output = phash::phash( path ),
-        "encode" => output = encode( path, start_sequence ),
-        "decode" => output = decode( path, secret_key ),
+        "edge" => {
+            let color = args.color.as_deref().map( edge_detection::ColorSpace::parse );
+            output = edge( path, edge_detection::Operator::parse( &args.operator ), &args.canny_params(), color, args.direction );
+        },
+        "phash" => {
+            output = match args.hash_config() {
+                Ok( config ) if args.animated => hash_animation( path, &config ),
+                Ok( config ) => hash( path, &config, args.base64, args.crop_resistant ),
+                Err( error ) => error,
+            };
+        },
+        "compare" => {
+            let second = args.data.clone().expect( "Compare needs a second image or hash. Aborting." );
+            output = match args.hash_config() {
+                Ok( config ) if args.animated => compare_sequences( path, second, &config, args.threshold, args.frame_match ),
+                Ok( config ) if args.crop_resistant => compare_regions( path, second, &config, args.threshold, args.regions ),
+                Ok( config ) => compare( path, second, &config, args.threshold ),
+                Err( error ) => error,
+            };
+        },
+        "dedupe" => output = dedupe( path, &args ),
+        "index" => output = index( path, &args ),
+        "query" => output = query( path, &args ),
+        "encode" => output = encode( path, start_sequence, &secret_key, &args.mode, &args.cost ),
+        "decode" if args.scan => output = scan( path, secret_key ),
+        "decode" => output = decode( path, secret_key, &args.mode ),
+        "analyze" => output = analyze( path ),
+        "forensics" => {
+            let method = args.data.clone().expect( "Forensics needs a method (copymove, ela, jpeg-ghost). Aborting." );
+            output = match method.as_str() {
+                "copymove" => copy_move( path ),
+                "ela" => error_level( path, args.quality ),
+                "jpeg-ghost" => jpeg_ghost( path, args.sheet ),
+                _ => format!( "Unknown forensics method {method}. Expected one of: copymove, ela, jpeg-ghost" ),
+            };
+        },
+        "filter" => {
+            let name = args.data.clone().expect( "Filter needs a method (gaussian, box, sharpen, unsharp, median, bilateral). Aborting." );
+            output = match args.filter( &name ) {
+                Some( method ) => filter( path, &method, convolution::Border::parse( &args.border ) ),
+                None => format!( "Unknown filter {name}. Expected one of: gaussian, box, sharpen, unsharp, median, bilateral" ),
+            };
+        },
+        "convolve" => {
+            let input = args.data.clone().expect( "Convolve needs a kernel, or a file containing one. Aborting." );
+            output = match load_kernel( &input ) {
+                Ok( kernel ) => {
+                    let kernel = if args.normalize { kernel.normalized() } else { kernel };
+                    convolve( path, &kernel, args.bias, args.gray, convolution::Border::parse( &args.border ) )
+                },
+                Err( error ) => error,
+            };
+        },
+        "lines" => output = lines( path, &args ),
+        "circles" => output = circles( path, &args ),
+        "bitplane" => output = bitplane( path, args.sheet ),
          _ => println!( "No matched process found. Aborting." )
     }
 
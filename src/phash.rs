@@ -1,31 +1,635 @@
-use image;
+use std::fmt;
 
-fn average(numbers: &[i32]) -> f32 {
-    numbers.iter().sum::<i32>() as f32 / numbers.len() as f32
+use image::{DynamicImage, GrayImage};
+use image::imageops::FilterType;
+
+use crate::dct::Dct;
+
+// -------------------------------------------------------------------------------------------------
+// Hash
+// -------------------------------------------------------------------------------------------------
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The bits of an image hash, packed most significant bit first into 64 bit words.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Hash {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl Hash {
+    pub fn from_bits(bits: &[bool]) -> Hash {
+        let mut words = vec![0u64; bits.len().div_ceil(64)];
+        for (i, bit) in bits.iter().enumerate() {
+            if *bit {
+                words[i / 64] |= 1 << (63 - i % 64);
+            }
+        }
+
+        Hash { words, len: bits.len() }
+    }
+
+    /// Set a bit for every value above the threshold.
+    fn threshold(values: &[f32], threshold: f32) -> Hash {
+        let bits: Vec<bool> = values.iter().map(|value| *value > threshold).collect();
+        Hash::from_bits(&bits)
+    }
+
+    /// Number of bits in the hash
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The hash as bytes, padding the last byte with zeros.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        bytes.truncate(self.len.div_ceil(8));
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8], len: usize) -> Hash {
+        let bits: Vec<bool> = (0..len).map(|i| (bytes[i / 8] >> (7 - i % 8)) & 1 == 1).collect();
+        Hash::from_bits(&bits)
+    }
+
+    /// Prefix the encoded bits with the length if it isn't a whole number of `unit` bits, since
+    /// the encoding alone can't tell padding from bits.
+    fn with_len(&self, encoded: String, unit: usize) -> String {
+        if self.len.is_multiple_of(unit) {
+            encoded
+        } else {
+            format!("{}:{}", self.len, encoded)
+        }
+    }
+
+    /// Split off the length written by `with_len`, checking it fits in `available` bits, where
+    /// the last `unit` bits may be padding. Without one, every bit is used.
+    fn split_len(text: &str, unit: usize, available: impl Fn(&str) -> usize) -> Option<(usize, &str)> {
+        match text.split_once(':') {
+            Some((len, encoded)) => {
+                let len: usize = len.parse().ok()?;
+                let available = available(encoded);
+                if len > available || len + unit <= available {
+                    return None;
+                }
+                Some((len, encoded))
+            },
+            None => Some((available(text), text)),
+        }
+    }
+
+    /// The hash in hex, one digit per four bits, with the length in front if it isn't a multiple
+    /// of four, e.g. `25:89989f0`.
+    pub fn to_hex(&self) -> String {
+        let digits: String = self.to_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.with_len(digits[..self.len.div_ceil(4)].to_string(), 4)
+    }
+
+    /// Parse a hash written by `to_hex`.
+    pub fn from_hex(text: &str) -> Option<Hash> {
+        let (len, hex) = Hash::split_len(text, 4, |hex| hex.len() * 4)?;
+        let mut bytes = Vec::with_capacity(hex.len().div_ceil(2));
+        for (i, digit) in hex.chars().enumerate() {
+            let value = digit.to_digit(16)? as u8;
+            if i % 2 == 0 {
+                bytes.push(value << 4);
+            } else {
+                *bytes.last_mut()? |= value;
+            }
+        }
+
+        Some(Hash::from_bytes(&bytes, len))
+    }
+
+    /// The hash in padded base64, with the length in front if it isn't a whole number of bytes.
+    pub fn to_base64(&self) -> String {
+        let bytes = self.to_bytes();
+        let mut encoded = String::new();
+
+        for chunk in bytes.chunks(3) {
+            let group = (chunk[0] as u32) << 16
+                | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+                | *chunk.get(2).unwrap_or(&0) as u32;
+
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    encoded.push(BASE64_ALPHABET[(group >> (18 - 6 * i) & 63) as usize] as char);
+                } else {
+                    encoded.push('=');
+                }
+            }
+        }
+
+        self.with_len(encoded, 8)
+    }
+
+    /// Parse a hash written by `to_base64`.
+    pub fn from_base64(text: &str) -> Option<Hash> {
+        let (len, encoded) = Hash::split_len(text, 8, |encoded| encoded.trim_end_matches('=').len() * 6 / 8 * 8)?;
+        let trimmed = encoded.trim_end_matches('=');
+        let mut bytes = Vec::new();
+        let mut group: u32 = 0;
+        let mut count = 0;
+
+        for character in trimmed.bytes() {
+            let value = BASE64_ALPHABET.iter().position(|c| *c == character)? as u32;
+            group = (group << 6) | value;
+            count += 6;
+
+            if count >= 8 {
+                count -= 8;
+                bytes.push((group >> count) as u8);
+            }
+        }
+
+        Some(Hash::from_bytes(&bytes, len))
+    }
+
+    /// Parse a hash written by `to_hex` or `to_base64`. Hex is tried first.
+    pub fn parse(text: &str) -> Option<Hash> {
+        Hash::from_hex(text).or_else(|| Hash::from_base64(text))
+    }
+
+    /// Number of bits that differ between two hashes, or `None` if they aren't the same length
+    /// and so can't be compared.
+    pub fn distance(&self, other: &Hash) -> Option<u32> {
+        if self.len != other.len {
+            return None;
+        }
+
+        Some(self.words.iter().zip(other.words.iter()).map(|(a, b)| (a ^ b).count_ones()).sum())
+    }
+
+    /// Fraction of bits that are the same, from 0 to 1, or `None` if the hashes aren't the same
+    /// length.
+    pub fn similarity(&self, other: &Hash) -> Option<f32> {
+        Some(1.0 - self.distance(other)? as f32 / self.len as f32)
+    }
 }
 
-fn vector_as_u8_array(vector: &Vec<u8>) -> [i32;64] {
-    let mut arr = [0i32;64];
-    for (place, element) in arr.iter_mut().zip(vector.iter()) {
-        *place = *element as i32;
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_hex())
     }
-    arr
 }
 
-pub fn phash(filepath: String) -> String {
-    let img = image::open(filepath).unwrap();
-    let thumb = img.thumbnail(8, 8).into_luma_alpha8().into_raw();
-    let avg_val = average(&vector_as_u8_array(&thumb));
-    let mut hash: Vec<char> = vec![];
+/// Something that can reduce an image to a `Hash`, where similar images give similar hashes.
+pub trait ImageHasher {
+    fn hash(&self, image: &DynamicImage) -> Hash;
+}
+
+/// Names accepted by `hasher`.
+pub const ALGORITHMS: [&str; 6] = ["phash", "ahash", "dhash", "whash", "blockmean", "colormoment"];
+
+/// Find a hasher by name, as passed on the command line. `size` is the width and height of the
+/// grid of bits, so 8 gives a 64 bit hash. Sizes the algorithm can't produce are an error here
+/// rather than a panic once hashing has started.
+pub fn hasher(name: &str, size: u32) -> Result<Box<dyn ImageHasher>, String> {
+    if size < 2 {
+        return Err(format!("Hash size must be at least 2, not {size}"));
+    }
+
+    Ok(match name {
+        "phash" => Box::new(PerceptualHash { size }),
+        "ahash" => Box::new(AverageHash { size }),
+        "dhash" => Box::new(DifferenceHash { size }),
+        "whash" if !size.is_power_of_two() => return Err(format!("Wavelet hash size must be a power of two, not {size}")),
+        "whash" => Box::new(WaveletHash { size }),
+        "blockmean" => Box::new(BlockMeanHash { size }),
+        "colormoment" => Box::new(ColorMomentHash),
+        _ => return Err(format!("Unknown hash algorithm {name}. Expected one of: {}", ALGORITHMS.join(", ")))
+    })
+}
 
-    for pix in thumb.iter() {
-        if *pix as f32 >= avg_val.floor() {
-            hash.push('1')
+/// Which hasher to use and how, as passed on the command line. Built with `new`, which checks
+/// that the algorithm exists and supports the size.
+#[derive(Debug, Clone)]
+pub struct HashConfig {
+    algo: String,
+    /// Width and height of the grid of bits, so 8 gives a 64 bit hash
+    size: u32,
+    /// Hash every rotation and flip of the image and keep the smallest, so that rotated and
+    /// mirrored copies hash the same
+    dihedral: bool,
+}
+
+impl HashConfig {
+    pub fn new(algo: &str, size: u32, dihedral: bool) -> Result<HashConfig, String> {
+        hasher(algo, size)?;
+        Ok(HashConfig { algo: algo.to_string(), size, dihedral })
+    }
+
+    pub fn hasher(&self) -> Box<dyn ImageHasher> {
+        let inner = hasher(&self.algo, self.size).expect("HashConfig::new checks the algorithm and size");
+
+        if self.dihedral {
+            Box::new(DihedralHash { inner })
         } else {
-            hash.push('0')
+            inner
         }
     }
 
-    return hash.into_iter().collect()
+    /// Number of bits in the hashes this configuration gives.
+    pub fn bits(&self) -> usize {
+        match self.algo.as_str() {
+            "colormoment" => 144,
+            _ => (self.size * self.size) as usize,
+        }
+    }
+
+    /// Hashes from different configurations can't be compared, so they need storing separately.
+    pub fn key(&self) -> String {
+        if self.dihedral {
+            format!("{}-{}-dihedral", self.algo, self.size)
+        } else {
+            format!("{}-{}", self.algo, self.size)
+        }
+    }
+}
 
-}
\ No newline at end of file
+// -------------------------------------------------------------------------------------------------
+// Helper functions
+// -------------------------------------------------------------------------------------------------
+
+fn average(numbers: &[f32]) -> f32 {
+    numbers.iter().sum::<f32>() / numbers.len() as f32
+}
+
+fn median(numbers: &[f32]) -> f32 {
+    let mut sorted = numbers.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let middle = sorted.len() / 2;
+    if sorted.len() & 1 == 0 {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+/// Shrink to a grayscale image of exactly `width` x `height`.
+fn shrink(image: &DynamicImage, width: u32, height: u32) -> GrayImage {
+    image.resize_exact(width, height, FilterType::Lanczos3).into_luma8()
+}
+
+/// Pixel values of a grayscale image indexed `[x][y]` like the rest of the crate.
+pub fn columns(gray: &GrayImage) -> Vec<Vec<f32>> {
+    let mut pixels = vec![vec![0.0; gray.height() as usize]; gray.width() as usize];
+    for (x, y, pixel) in gray.enumerate_pixels() {
+        pixels[x as usize][y as usize] = pixel.0[0] as f32;
+    }
+    pixels
+}
+
+// -------------------------------------------------------------------------------------------------
+// Hashers
+// -------------------------------------------------------------------------------------------------
+
+/// Shrink to `size` x `size` and compare every pixel against the mean.
+pub struct AverageHash {
+    pub size: u32,
+}
+
+impl ImageHasher for AverageHash {
+    fn hash(&self, image: &DynamicImage) -> Hash {
+        let pixels: Vec<f32> = shrink(image, self.size, self.size).pixels().map(|p| p.0[0] as f32).collect();
+        Hash::threshold(&pixels, average(&pixels))
+    }
+}
+
+/// Shrink to a grayscale image four times the hash size, take the 2D DCT and compare the
+/// `size` x `size` lowest frequencies against their median.
+pub struct PerceptualHash {
+    pub size: u32,
+}
+
+impl ImageHasher for PerceptualHash {
+    fn hash(&self, image: &DynamicImage) -> Hash {
+        let dct_size = self.size * 4;
+        let pixels = columns(&shrink(image, dct_size, dct_size));
+        let dct = Dct::new(dct_size as usize).process_2d(&pixels);
+
+        let mut low_frequencies = Vec::with_capacity((self.size * self.size) as usize);
+        for y in 0..self.size as usize {
+            for column in dct.iter().take(self.size as usize) {
+                low_frequencies.push(column[y]);
+            }
+        }
+
+        Hash::threshold(&low_frequencies, median(&low_frequencies))
+    }
+}
+
+/// Shrink to `size + 1` x `size` and set a bit wherever brightness increases to the right.
+pub struct DifferenceHash {
+    pub size: u32,
+}
+
+impl ImageHasher for DifferenceHash {
+    fn hash(&self, image: &DynamicImage) -> Hash {
+        let gray = shrink(image, self.size + 1, self.size);
+        let mut bits = Vec::with_capacity((self.size * self.size) as usize);
+
+        for y in 0..self.size {
+            for x in 0..self.size {
+                bits.push(gray.get_pixel(x + 1, y).0[0] > gray.get_pixel(x, y).0[0]);
+            }
+        }
+
+        Hash::from_bits(&bits)
+    }
+}
+
+/// Shrink to four times the hash size, run a Haar wavelet decomposition down to `size` x `size`
+/// and compare the remaining low frequency coefficients against their median. `size` must be a
+/// power of two.
+pub struct WaveletHash {
+    pub size: u32,
+}
+
+impl WaveletHash {
+    /// One level of the 2D Haar transform, keeping only the low-low band.
+    fn haar_low(pixels: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let half_width = pixels.len() / 2;
+        let half_height = pixels[0].len() / 2;
+        let mut low = vec![vec![0.0; half_height]; half_width];
+
+        for (x, column) in low.iter_mut().enumerate() {
+            for (y, value) in column.iter_mut().enumerate() {
+                *value = (pixels[2 * x][2 * y] + pixels[2 * x + 1][2 * y]
+                    + pixels[2 * x][2 * y + 1] + pixels[2 * x + 1][2 * y + 1]) / 2.0;
+            }
+        }
+
+        low
+    }
+}
+
+impl ImageHasher for WaveletHash {
+    fn hash(&self, image: &DynamicImage) -> Hash {
+        let scale = self.size * 4;
+        let mut pixels = columns(&shrink(image, scale, scale));
+        for value in pixels.iter_mut().flatten() {
+            *value /= 255.0;
+        }
+
+        while pixels.len() > self.size as usize {
+            pixels = WaveletHash::haar_low(&pixels);
+        }
+
+        let mut coefficients = Vec::with_capacity((self.size * self.size) as usize);
+        for y in 0..self.size as usize {
+            for column in pixels.iter() {
+                coefficients.push(column[y]);
+            }
+        }
+
+        Hash::threshold(&coefficients, median(&coefficients))
+    }
+}
+
+/// Split the grayscale image into `size` x `size` blocks and compare the mean of each block
+/// against the median of all of them.
+pub struct BlockMeanHash {
+    pub size: u32,
+}
+
+impl ImageHasher for BlockMeanHash {
+    fn hash(&self, image: &DynamicImage) -> Hash {
+        // Work on a fixed size so that every block covers the same number of pixels
+        let block = 16;
+        let gray = shrink(image, self.size * block, self.size * block);
+        let mut means = Vec::with_capacity((self.size * self.size) as usize);
+
+        for block_y in 0..self.size {
+            for block_x in 0..self.size {
+                let mut sum = 0.0;
+                for y in 0..block {
+                    for x in 0..block {
+                        sum += gray.get_pixel(block_x * block + x, block_y * block + y).0[0] as f32;
+                    }
+                }
+                means.push(sum / (block * block) as f32);
+            }
+        }
+
+        Hash::threshold(&means, median(&means))
+    }
+}
+
+/// The 8 rotations and reflections of an image (the dihedral group of the square).
+pub fn dihedral_transforms(image: &DynamicImage) -> Vec<DynamicImage> {
+    let mut transforms = Vec::with_capacity(8);
+
+    for base in [image.clone(), image.fliph()] {
+        transforms.push(base.rotate90());
+        transforms.push(base.rotate180());
+        transforms.push(base.rotate270());
+        transforms.push(base);
+    }
+
+    transforms
+}
+
+/// Wraps another hasher, hashing all 8 rotations and reflections and keeping the smallest hash.
+/// Every transform of an image produces the same set of hashes, so they all pick the same one.
+pub struct DihedralHash {
+    pub inner: Box<dyn ImageHasher>,
+}
+
+impl ImageHasher for DihedralHash {
+    fn hash(&self, image: &DynamicImage) -> Hash {
+        dihedral_transforms(image).iter()
+            .map(|transform| self.inner.hash(transform))
+            .min()
+            .expect("No transforms to hash")
+    }
+}
+
+/// Mean, standard deviation and skewness of each HSV and YCbCr channel, each quantized to a byte
+/// and Gray coded so that small changes only flip a few bits. Always 144 bits, the hash size
+/// doesn't apply.
+pub struct ColorMomentHash;
+
+impl ColorMomentHash {
+    /// Convert an RGB pixel to HSV and YCbCr, all scaled to 0..1.
+    fn channels(pixel: &[u8]) -> [f32; 6] {
+        let r = pixel[0] as f32 / 255.0;
+        let g = pixel[1] as f32 / 255.0;
+        let b = pixel[2] as f32 / 255.0;
+
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let hue = if delta == 0.0 {
+            0.0
+        } else if max == r {
+            ((g - b) / delta).rem_euclid(6.0) / 6.0
+        } else if max == g {
+            ((b - r) / delta + 2.0) / 6.0
+        } else {
+            ((r - g) / delta + 4.0) / 6.0
+        };
+        let saturation = if max == 0.0 { 0.0 } else { delta / max };
+
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+        let cb = 0.5 + (b - luma) * 0.564;
+        let cr = 0.5 + (r - luma) * 0.713;
+
+        [hue, saturation, max, luma, cb, cr]
+    }
+
+    /// Quantize a value in `min..max` to a byte and Gray code it.
+    fn encode(value: f32, min: f32, max: f32, bits: &mut Vec<bool>) {
+        let quantized = (((value - min) / (max - min)).clamp(0.0, 1.0) * 255.0).round() as u8;
+        let gray = quantized ^ (quantized >> 1);
+
+        for shift in (0..8).rev() {
+            bits.push((gray >> shift) & 1 == 1);
+        }
+    }
+}
+
+impl ImageHasher for ColorMomentHash {
+    fn hash(&self, image: &DynamicImage) -> Hash {
+        // Smooth out noise before measuring the distribution
+        let rgb = image.resize_exact(64, 64, FilterType::Gaussian).into_rgb8();
+        let count = (rgb.width() * rgb.height()) as f32;
+
+        let converted: Vec<[f32; 6]> = rgb.pixels().map(|pixel| ColorMomentHash::channels(&pixel.0)).collect();
+        let mut bits = Vec::with_capacity(6 * 3 * 8);
+
+        for channel in 0..6 {
+            let mean = converted.iter().map(|values| values[channel]).sum::<f32>() / count;
+            let variance = converted.iter().map(|values| (values[channel] - mean).powi(2)).sum::<f32>() / count;
+            let deviation = variance.sqrt();
+            let skew = if deviation > 0.0 {
+                converted.iter().map(|values| ((values[channel] - mean) / deviation).powi(3)).sum::<f32>() / count
+            } else {
+                0.0
+            };
+
+            ColorMomentHash::encode(mean, 0.0, 1.0, &mut bits);
+            ColorMomentHash::encode(deviation, 0.0, 0.5, &mut bits);
+            ColorMomentHash::encode(skew, -4.0, 4.0, &mut bits);
+        }
+
+        Hash::from_bits(&bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hash of `size * size` bits with an irregular pattern, so no padding lines up by chance.
+    fn pattern(size: usize) -> Hash {
+        let bits: Vec<bool> = (0..size * size).map(|i| (i * 7 + i / 3) % 5 < 2).collect();
+        Hash::from_bits(&bits)
+    }
+
+    #[test]
+    fn hex_round_trips_any_length() {
+        for size in [5, 7, 8] {
+            let hash = pattern(size);
+            let parsed = Hash::from_hex(&hash.to_hex()).unwrap();
+
+            assert_eq!(parsed, hash, "size {size}");
+            assert_eq!(parsed.distance(&hash), Some(0));
+        }
+    }
+
+    #[test]
+    fn base64_round_trips_any_length() {
+        for size in [5, 7, 8] {
+            let hash = pattern(size);
+            assert_eq!(Hash::from_base64(&hash.to_base64()).unwrap(), hash, "size {size}");
+        }
+    }
+
+    #[test]
+    fn length_is_only_written_when_needed() {
+        assert!(pattern(5).to_hex().starts_with("25:"));
+        assert!(pattern(5).to_base64().starts_with("25:"));
+        assert!(!pattern(8).to_hex().contains(':'));
+        assert!(!pattern(8).to_base64().contains(':'));
+    }
+
+    #[test]
+    fn parse_rejects_lengths_that_dont_fit() {
+        assert!(Hash::from_hex("29:89989f0").is_none());
+        assert!(Hash::from_hex("24:89989f0").is_none());
+        assert_eq!(Hash::from_hex("25:89989f0").unwrap().len(), 25);
+        assert_eq!(Hash::from_hex("89989f0").unwrap().len(), 28);
+    }
+
+    #[test]
+    fn hashes_of_different_lengths_dont_compare() {
+        assert_eq!(pattern(5).distance(&pattern(7)), None);
+        assert_eq!(pattern(5).similarity(&pattern(7)), None);
+    }
+
+    /// An image with no symmetry, so every rotation and flip looks different.
+    fn asymmetric() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, y| {
+            image::Luma([if x < 20 && y < 40 { 255 } else { ((x * 3 + y * y / 16) % 200) as u8 }])
+        }))
+    }
+
+    fn dihedral(algo: &str) -> HashConfig {
+        HashConfig::new(algo, 8, true).unwrap()
+    }
+
+    #[test]
+    fn dihedral_hashes_match_rotated_and_flipped_copies() {
+        let image = asymmetric();
+        let copies = [image.rotate90(), image.rotate180(), image.rotate270(), image.fliph(), image.flipv()];
+
+        for algo in ["phash", "ahash", "dhash"] {
+            let hasher = dihedral(algo).hasher();
+            let original = hasher.hash(&image);
+
+            for copy in copies.iter() {
+                assert_eq!(hasher.hash(copy), original, "{algo}");
+            }
+        }
+    }
+
+    #[test]
+    fn plain_hashes_tell_rotated_copies_apart() {
+        let hasher = hasher("phash", 8).unwrap();
+        let image = asymmetric();
+
+        assert!(hasher.hash(&image).distance(&hasher.hash(&image.rotate90())).unwrap() > 10);
+    }
+
+    #[test]
+    fn dihedral_hashes_still_tell_different_images_apart() {
+        let hasher = dihedral("phash").hasher();
+        let other = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 64, |x, y| image::Luma([((x / 8 + y / 8) % 2 * 255) as u8])));
+
+        assert!(hasher.hash(&asymmetric()).distance(&hasher.hash(&other)).unwrap() > 0);
+    }
+
+    #[test]
+    fn unsupported_sizes_are_rejected_up_front() {
+        assert!(hasher("whash", 6).is_err());
+        assert!(hasher("whash", 8).is_ok());
+        assert!(hasher("phash", 1).is_err());
+        assert!(hasher("nope", 8).is_err());
+        assert!(HashConfig::new("whash", 12, true).is_err());
+    }
+
+    #[test]
+    fn image_hashes_of_odd_sizes_round_trip() {
+        let image = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 48, |x, y| image::Luma([((x * x + 3 * y) % 256) as u8])));
+
+        for algo in ["phash", "ahash", "dhash", "blockmean"] {
+            let hash = hasher(algo, 5).unwrap().hash(&image);
+            assert_eq!(Hash::parse(&hash.to_hex()).unwrap(), hash, "{algo}");
+            assert_eq!(Hash::parse(&hash.to_base64()).unwrap(), hash, "{algo}");
+        }
+    }
+}
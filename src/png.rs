@@ -1,4 +1,14 @@
-use image::{GenericImageView, DynamicImage};
+use image::{GenericImageView, DynamicImage, RgbaImage};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rayon::prelude::*;
+
+use crate::{crypt, stc};
+use crate::image_f32::ImageF32;
+
+/// Number of pixels at the start of the image that hold the message length for STC encoding.
+const STC_HEADER_BITS: usize = 32;
 
 fn binary_string_to_u8(str: &str) -> u8 {
     let mut val: u8 = 0;
@@ -59,62 +69,60 @@ impl PNG {
         return thumb_path
     }
     
-    pub fn encode(&self, data: Vec<u8>) -> String {
-        let mut new_image = image::RgbaImage::new(self.width, self.height);
-        let mut bit_string: Vec<String> = Vec::new();
+    fn encoded_path(&self) -> String {
         let path_split: Vec<&str> = self.path.split(".").collect();
-        let enc_path;
 
         if path_split.len() == 3 {
-            enc_path = format!(".{}{}_enc.{}", path_split[0], path_split[1], path_split[2]);
+            format!(".{}{}_enc.{}", path_split[0], path_split[1], path_split[2])
         } else {
-            enc_path = format!(".{}_enc.{}", path_split[0], path_split[1]);
-        }
-
-        for x in 0..data.len() {
-            bit_string.push( format!("{:08b}", data[x]) );
+            format!(".{}_enc.{}", path_split[0], path_split[1])
         }
+    }
 
-        let joined = bit_string.join("");
-        let joined_as_bytes = joined.as_bytes();
-        let mut i = 0 as usize;
+    /// Encode using LSB replacement. Values always move in the same direction for a given parity,
+    /// which is what chi-square attacks pick up on.
+    pub fn encode(&self, data: Vec<u8>) -> String {
+        self.save_encoded(self.embed_with(data, |_, value, bit| set_lsb(value, bit)))
+    }
 
-        for (x, y, pixel) in self.image.pixels() {
+    /// Encode using LSB matching. Values that need their LSB changed are randomly moved up or down
+    /// by one using an RNG seeded from `secret_key`. Decoding is unchanged since only the parity
+    /// matters.
+    pub fn encode_matching(&self, data: Vec<u8>, secret_key: &str) -> String {
+        self.save_encoded(self.embed_matching(data, secret_key))
+    }
 
-            let mut new_pix = pixel.clone();
-
-            // Perform LSB operation
-            // ---------------------
-            // If the bit is 1 and the pixel red value is even, we convert. If odd, leave it alone.
-            // If the bit is 0 and the pixel red value is odd, we convert. If even, leave it alone.
-            if joined_as_bytes[i] == 49 {
-                if new_pix.0[0] % 2 == 0 {
-                    if new_pix.0[0] > 0 {
-                        new_pix.0[0] = new_pix.0[0] - 1;
-                    } else {
-                        new_pix.0[0] = new_pix.0[0] + 1;
-                    }
-                }
-            } else {
-                if new_pix.0[0] % 2 != 0 {
-                    if new_pix.0[0] == 255 {
-                        new_pix.0[0] = new_pix.0[0] - 1;
-                    } else {
-                        new_pix.0[0] = new_pix.0[0] + 1;
-                    }
-                }
-            }
+    fn embed_matching(&self, data: Vec<u8>, secret_key: &str) -> RgbaImage {
+        // Draw a direction for every pixel up front so the result doesn't depend on the order
+        // pixels are processed in
+        let mut rng = StdRng::from_seed(crypt::seed(secret_key));
+        let directions: Vec<bool> = (0..self.total_pixels()).map(|_| rng.gen()).collect();
 
-            new_image.put_pixel(x, y, new_pix);
+        self.embed_with(data, |i, value, bit| match_lsb(value, bit, directions[i]))
+    }
 
-            i += 1;
+    /// Embed the bits of `data` into the red channel, repeating them until every pixel is used.
+    /// `embed` is given the pixel index, the current value and the bit to embed.
+    fn embed_with(&self, data: Vec<u8>, embed: impl Fn(usize, u8, u8) -> u8 + Sync) -> RgbaImage {
+        let mut new_image = self.image.to_rgba8();
 
-            if i >= joined_as_bytes.len() {
-                i = 0
+        let mut bits: Vec<u8> = Vec::with_capacity(data.len() * 8);
+        for byte in data.iter() {
+            for shift in (0..8).rev() {
+                bits.push((byte >> shift) & 1);
             }
         }
 
-        new_image.save( enc_path.clone() ).expect( "Failed to save encoded image" );
+        new_image.par_chunks_mut(4).enumerate().for_each(|(i, pixel)| {
+            pixel[0] = embed(i, pixel[0], bits[i % bits.len()]);
+        });
+
+        new_image
+    }
+
+    fn save_encoded(&self, image: RgbaImage) -> String {
+        let enc_path = self.encoded_path();
+        image.save( enc_path.clone() ).expect( "Failed to save encoded image" );
 
         return enc_path
     }
@@ -156,6 +164,121 @@ impl PNG {
 
         return final_vec;
     }
+
+    /// Encode using syndrome-trellis codes so that the pixels that get changed are the ones the
+    /// distortion function considers cheapest. The first `STC_HEADER_BITS` pixels hold the
+    /// message length in plain LSBs, the rest carry the message as a syndrome.
+    pub fn encode_stc(&self, data: Vec<u8>, distortion: &dyn stc::Distortion) -> Result<String, String> {
+        let enc_path = self.encoded_path();
+
+        let new_image = self.embed_stc(data, distortion)?;
+        new_image.save( enc_path.clone() ).expect( "Failed to save encoded image" );
+
+        Ok(enc_path)
+    }
+
+    fn embed_stc(&self, data: Vec<u8>, distortion: &dyn stc::Distortion) -> Result<RgbaImage, String> {
+        let mut new_image = self.image.to_rgba8();
+        if (new_image.len() / 4) <= STC_HEADER_BITS {
+            return Err(format!("STC needs more than {STC_HEADER_BITS} pixels to hold the message length"));
+        }
+
+        let costs = distortion.costs(&ImageF32::from_channel(&new_image, 0));
+
+        // Both are row-major, so pixels and costs line up
+        let cover: Vec<u8> = new_image.pixels().map(|pixel| pixel.0[0] & 1).collect();
+        let pixel_costs: Vec<f64> = costs.values().map(|cost| *cost as f64).collect();
+
+        let mut message = Vec::with_capacity(data.len() * 8);
+        for byte in data.iter() {
+            for shift in (0..8).rev() {
+                message.push((byte >> shift) & 1);
+            }
+        }
+
+        let mut stego = Vec::with_capacity(cover.len());
+        for shift in (0..STC_HEADER_BITS).rev() {
+            stego.push(((message.len() >> shift) & 1) as u8);
+        }
+        let mut embedded = stc::embed(&cover[STC_HEADER_BITS..], &pixel_costs[STC_HEADER_BITS..], &message)
+            .ok_or("Data is too large to be encoded into this image")?;
+        stego.append(&mut embedded);
+
+        for (i, pixel) in new_image.pixels_mut().enumerate() {
+            if stego[i] != cover[i] {
+                pixel.0[0] = set_lsb(pixel.0[0], stego[i]);
+            }
+        }
+
+        Ok(new_image)
+    }
+
+    /// Decode a message written by `encode_stc`. Returns an empty message if the image doesn't
+    /// hold one.
+    pub fn decode_stc(&self) -> Result<Vec::<u8>, String> {
+        let stego: Vec<u8> = self.image.to_rgba8().pixels().map(|pixel| pixel.0[0] & 1).collect();
+        if stego.len() <= STC_HEADER_BITS {
+            return Err(format!("STC needs more than {STC_HEADER_BITS} pixels to hold the message length"));
+        }
+
+        let mut message_len = 0;
+        for bit in stego[..STC_HEADER_BITS].iter() {
+            message_len = (message_len << 1) | *bit as usize;
+        }
+
+        // Anything longer than the cover can't have been written by `encode_stc`
+        if message_len == 0 || message_len > stego.len() - STC_HEADER_BITS {
+            return Ok(Vec::new());
+        }
+
+        let bits = stc::extract(&stego[STC_HEADER_BITS..], message_len);
+        let mut data: Vec<u8> = bits.chunks(8)
+            .map(|byte| byte.iter().fold(0, |acc, bit| (acc << 1) | bit))
+            .collect();
+
+        if data.len() < 12 || !check_for_end(data.clone(), "======".as_bytes()) {
+            return Ok(Vec::new());
+        }
+
+        data.truncate(data.len() - 6);
+        Ok(data[6..].to_vec())
+    }
+}
+
+/// Set the least significant bit of a channel value by moving it one step, staying in range.
+/// If the bit is 1 and the value is even, we convert. If odd, leave it alone.
+/// If the bit is 0 and the value is odd, we convert. If even, leave it alone.
+fn set_lsb(value: u8, bit: u8) -> u8 {
+    if bit == 1 {
+        if value & 1 == 0 {
+            if value > 0 {
+                return value - 1;
+            }
+            return value + 1;
+        }
+    } else if value & 1 == 1 {
+        if value == 255 {
+            return value - 1;
+        }
+        return value + 1;
+    }
+
+    value
+}
+
+/// Set the least significant bit of a channel value by adding one if `up` or subtracting one
+/// otherwise, staying in range.
+fn match_lsb(value: u8, bit: u8, up: bool) -> u8 {
+    if value & 1 == bit {
+        return value;
+    }
+
+    match value {
+        0 => 1,
+        255 => 254,
+        _ if up => value + 1,
+        _ => value - 1
+    }
 }
 
 fn check_for_end(mut vec: Vec<u8>, end: &[u8]) -> bool {
@@ -176,4 +299,100 @@ fn check_for_end(mut vec: Vec<u8>, end: &[u8]) -> bool {
 
 pub fn create(filepath: String) -> PNG {
     return PNG::new(filepath);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(image: DynamicImage) -> PNG {
+        PNG {
+            path: String::from("./test.png"),
+            width: image.width(),
+            height: image.height(),
+            alpha: true,
+            channels: 4,
+            image
+        }
+    }
+
+    /// Noisy cover so the HILL costs vary across the image.
+    fn cover(width: u32, height: u32) -> DynamicImage {
+        let mut rng = StdRng::seed_from_u64(26);
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |_, _| image::Rgba([rng.gen(), rng.gen(), rng.gen(), 255])))
+    }
+
+    #[test]
+    fn stc_round_trip_with_hill_costs() {
+        let cover = png(cover(64, 64));
+        let data = b"######secret message======".to_vec();
+
+        let stego = cover.embed_stc(data, &stc::Hill).unwrap();
+        let changed = cover.image.to_rgba8().pixels().zip(stego.pixels()).filter(|(a, b)| a != b).count();
+        let decoded = png(DynamicImage::ImageRgba8(stego)).decode_stc().unwrap();
+
+        assert_eq!(decoded, b"secret message".to_vec());
+        // 208 message bits in 4064 pixels should need far fewer changes than plain LSB replacement
+        assert!(changed < 104, "{changed} pixels changed");
+    }
+
+    #[test]
+    fn stc_rejects_images_too_small_for_the_header() {
+        let tiny = png(cover(4, 4));
+
+        assert!(tiny.embed_stc(b"######hi======".to_vec(), &stc::Uniform).is_err());
+        assert!(tiny.decode_stc().is_err());
+    }
+
+    #[test]
+    fn lsb_matching_round_trip() {
+        let cover = png(cover(64, 64));
+
+        let stego = cover.embed_matching(b"######secret message======".to_vec(), "key");
+        let decoded = png(DynamicImage::ImageRgba8(stego.clone())).decode();
+
+        assert_eq!(decoded, b"secret message".to_vec());
+        // Only the red channel changes, by one either way
+        let (mut up, mut down) = (0, 0);
+        for (a, b) in cover.image.to_rgba8().pixels().zip(stego.pixels()) {
+            assert!(a.0[0].abs_diff(b.0[0]) <= 1);
+            assert_eq!(a.0[1..], b.0[1..]);
+            up += (b.0[0] > a.0[0]) as usize;
+            down += (b.0[0] < a.0[0]) as usize;
+        }
+        assert!(up > 0 && down > 0, "{up} up, {down} down");
+    }
+
+    #[test]
+    fn match_lsb_moves_both_ways_without_wrapping() {
+        assert_eq!(match_lsb(10, 1, true), 11);
+        assert_eq!(match_lsb(10, 1, false), 9);
+        assert_eq!(match_lsb(11, 0, true), 12);
+        assert_eq!(match_lsb(11, 0, false), 10);
+        assert_eq!(match_lsb(10, 0, true), 10);
+        assert_eq!(match_lsb(0, 1, false), 1);
+        assert_eq!(match_lsb(255, 0, true), 254);
+    }
+
+    #[test]
+    fn encoding_is_the_same_on_any_number_of_threads() {
+        let cover = png(cover(64, 64));
+        let encode = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            pool.install(|| (
+                cover.embed_with(b"######message======".to_vec(), |_, value, bit| set_lsb(value, bit)),
+                cover.embed_matching(b"######message======".to_vec(), "key"),
+            ))
+        };
+
+        let single = encode(1);
+        for threads in [2, 4, 7] {
+            assert_eq!(encode(threads), single, "{threads} threads");
+        }
+    }
+
+    #[test]
+    fn stc_decode_ignores_images_without_a_payload() {
+        assert_eq!(png(cover(64, 64)).decode_stc().unwrap(), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use image::RgbaImage;
+
+use crate::crypt;
+
+// -------------------------------------------------------------------------------------------------
+// Layouts
+// -------------------------------------------------------------------------------------------------
+
+/// Channel orders to try, as indices into RGBA.
+const CHANNEL_ORDERS: [(&str, &[usize]); 8] = [
+    ("R", &[0]),
+    ("G", &[1]),
+    ("B", &[2]),
+    ("A", &[3]),
+    ("RGB", &[0, 1, 2]),
+    ("BGR", &[2, 1, 0]),
+    ("RGBA", &[0, 1, 2, 3]),
+    ("BGRA", &[2, 1, 0, 3]),
+];
+
+/// Stop extracting after this many bytes. Enough to recognise a payload without pulling the whole
+/// image through every layout.
+const MAX_SCAN_BYTES: usize = 1 << 20;
+
+/// Shortest run of printable characters at the start of a layout that is reported as text.
+const MIN_TEXT_LENGTH: usize = 16;
+
+/// One way of reading hidden bits out of an image.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub channels: &'static str,
+    channel_indices: &'static [usize],
+    /// Bit plane to read from, 0 being the LSB
+    pub bit: u8,
+    /// Whether bits are packed into bytes most significant bit first
+    pub msb_first: bool,
+    /// Whether pixels are read column by column instead of row by row
+    pub column_major: bool,
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "b{},{},{},{}",
+            self.bit,
+            self.channels.to_lowercase(),
+            if self.msb_first { "msb" } else { "lsb" },
+            if self.column_major { "yx" } else { "xy" }
+        )
+    }
+}
+
+/// Every layout that `scan` tries.
+pub fn layouts() -> Vec<Layout> {
+    let mut layouts = Vec::new();
+
+    for (channels, channel_indices) in CHANNEL_ORDERS.iter() {
+        for bit in 0..2 {
+            for msb_first in [true, false] {
+                for column_major in [false, true] {
+                    layouts.push(Layout { channels, channel_indices, bit, msb_first, column_major });
+                }
+            }
+        }
+    }
+
+    layouts
+}
+
+/// Read up to `limit` bytes out of an image using the given layout.
+pub fn extract(image: &RgbaImage, layout: &Layout, limit: usize) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let (outer, inner) = if layout.column_major { (width, height) } else { (height, width) };
+
+    let mut bytes = Vec::new();
+    let mut current: u8 = 0;
+    let mut count = 0;
+
+    for a in 0..outer {
+        for b in 0..inner {
+            let (x, y) = if layout.column_major { (a, b) } else { (b, a) };
+            let pixel = image.get_pixel(x, y);
+
+            for channel in layout.channel_indices.iter() {
+                let bit = (pixel.0[*channel] >> layout.bit) & 1;
+
+                if layout.msb_first {
+                    current = (current << 1) | bit;
+                } else {
+                    current |= bit << count;
+                }
+                count += 1;
+
+                if count == 8 {
+                    bytes.push(current);
+                    if bytes.len() >= limit {
+                        return bytes;
+                    }
+                    current = 0;
+                    count = 0;
+                }
+            }
+        }
+    }
+
+    bytes
+}
+
+// -------------------------------------------------------------------------------------------------
+// Detection
+// -------------------------------------------------------------------------------------------------
+
+/// Something that looks like hidden data in a particular layout.
+#[derive(Debug)]
+pub struct Finding {
+    pub layout: String,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<16} {:<10} {}", self.layout, self.kind, self.detail)
+    }
+}
+
+/// Try every layout against every known format and report anything that decodes to plausible
+/// data. `password` is used for formats that encrypt their payload.
+pub fn scan(image: &RgbaImage, password: &str) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for layout in layouts() {
+        let bytes = extract(image, &layout, MAX_SCAN_BYTES);
+
+        let detected = acetic(&bytes, password)
+            .or_else(|| openstego(&bytes))
+            .or_else(|| file_signature(&bytes))
+            .or_else(|| text(&bytes));
+
+        if let Some((kind, detail)) = detected {
+            findings.push(Finding { layout: layout.to_string(), kind, detail });
+        }
+    }
+
+    // Steghide spreads its data over pixels picked by the password rather than a fixed layout
+    if let Some(detail) = steghide(image, password) {
+        findings.push(Finding { layout: String::from("seeded"), kind: "steghide", detail });
+    }
+
+    findings
+}
+
+/// Acetic's own `######` / `======` framing around an encrypted payload.
+fn acetic(bytes: &[u8], password: &str) -> Option<(&'static str, String)> {
+    let start = crate::START.as_bytes();
+    let end = crate::END.as_bytes();
+
+    if !bytes.starts_with(start) {
+        return None;
+    }
+
+    let length = bytes[start.len()..].windows(end.len()).position(|window| window == end)?;
+    let cipher_text = &bytes[start.len()..start.len() + length];
+
+    let detail = match crypt::try_decrypt(password.to_string(), cipher_text) {
+        Some(plain_text) => format!("\"{}\"", preview(&plain_text)),
+        None => format!("{} encrypted bytes, wrong password", cipher_text.len()),
+    };
+
+    Some(("acetic", detail))
+}
+
+/// OpenStego's LSB plugin header: the stamp, a version, then a little-endian data length, bits
+/// used per channel, file name length, compression and encryption flags and the file name.
+fn openstego(bytes: &[u8]) -> Option<(&'static str, String)> {
+    let stamp = "OPENSTEGO".as_bytes();
+    let fixed_length = stamp.len() + 1 + 8;
+
+    if !bytes.starts_with(stamp) || bytes.len() < fixed_length {
+        return None;
+    }
+
+    let header = &bytes[stamp.len() + 1..];
+    let data_length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let channel_bits = header[4];
+    let name_length = header[5] as usize;
+    let compressed = header[6] == 1;
+    let encrypted = header[7] == 1;
+
+    let name_start = fixed_length;
+    let data_start = name_start + name_length;
+    if bytes.len() < data_start {
+        return Some(("openstego", String::from("truncated header")));
+    }
+    let file_name = String::from_utf8_lossy(&bytes[name_start..data_start]);
+
+    let mut detail = format!("file \"{}\", {} bytes", file_name, data_length);
+
+    if encrypted {
+        detail.push_str(", encrypted (not supported)");
+    } else if channel_bits != 1 {
+        detail.push_str(&format!(", {} bits per channel (not supported)", channel_bits));
+    } else if bytes.len() >= data_start + data_length {
+        let mut data = bytes[data_start..data_start + data_length].to_vec();
+
+        if compressed {
+            data = inflate(&data).unwrap_or(data);
+        }
+
+        match file_signature(&data) {
+            Some((_, kind)) => detail.push_str(&format!(", {}", kind)),
+            None => detail.push_str(&format!(": \"{}\"", preview(&data))),
+        }
+    }
+
+    Some(("openstego", detail))
+}
+
+/// OpenStego compresses with gzip, but fall back on plain zlib.
+fn inflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    if GzDecoder::new(data).read_to_end(&mut output).is_ok() {
+        return Some(output);
+    }
+
+    output.clear();
+    ZlibDecoder::new(data).read_to_end(&mut output).ok()?;
+    Some(output)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Steghide
+// -------------------------------------------------------------------------------------------------
+//
+// Steghide hashes the password with MD5 to seed a generator that shuffles the pixels, and reads
+// one bit from each pair of pixels in that order: the parity of the red, green and blue LSBs of
+// both added together. The bits, least significant first, start with a 24 bit magic number, the
+// format version as a run of ones ended by a zero, the encryption algorithm and mode, and the
+// length of the payload. Pixels are numbered from the bottom row up, the order BMP files store
+// them in.
+
+const STEGHIDE_MAGIC: u32 = 0x73688D;
+const STEGHIDE_SAMPLES_PER_BIT: usize = 2;
+
+/// Encryption algorithms by the number steghide stores for them.
+const STEGHIDE_ALGORITHMS: [&str; 23] = [
+    "none", "twofish", "rijndael-128", "rijndael-192", "rijndael-256", "saferplus", "rc2", "xtea",
+    "serpent", "safer-sk64", "safer-sk128", "cast-256", "loki97", "gost", "threeway", "cast-128",
+    "blowfish", "des", "tripledes", "enigma", "arcfour", "panama", "wake",
+];
+
+const STEGHIDE_MODES: [&str; 8] = ["ecb", "cbc", "ofb", "cfb", "nofb", "ncfb", "ctr", "stream"];
+
+/// The MD5 digest of some data.
+fn md5(data: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: Vec<u32> = (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32).collect();
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+        let [mut a, mut b, mut c, mut d] = state;
+
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+
+            let rotated = a.wrapping_add(f).wrapping_add(constants[i]).wrapping_add(words[g])
+                .rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+            (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+        }
+
+        for (value, added) in state.iter_mut().zip([a, b, c, d]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    digest
+}
+
+/// The order steghide visits pixels in for a password: a Fisher-Yates shuffle driven by a linear
+/// congruential generator, worked out lazily since only the start is usually needed.
+struct SteghideOrder {
+    state: u32,
+    total: u32,
+    swapped: HashMap<u32, u32>,
+    next: u32,
+}
+
+impl SteghideOrder {
+    fn new(password: &str, total: u32) -> SteghideOrder {
+        let state = md5(password.as_bytes()).chunks(4)
+            .fold(0, |seed, word| seed ^ u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+
+        SteghideOrder { state, total, swapped: HashMap::new(), next: 0 }
+    }
+
+    /// A random number from 0 up to `n`.
+    fn random(&mut self, n: u32) -> u32 {
+        self.state = self.state.wrapping_mul(1367208549).wrapping_add(1);
+        (self.state as f64 / 4294967296.0 * n as f64) as u32
+    }
+}
+
+impl Iterator for SteghideOrder {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.next >= self.total {
+            return None;
+        }
+
+        let i = self.next;
+        let j = self.random(self.total - i) + i;
+        let at_i = *self.swapped.get(&i).unwrap_or(&i);
+        let at_j = *self.swapped.get(&j).unwrap_or(&j);
+        self.swapped.insert(j, at_i);
+        self.next += 1;
+
+        Some(at_j)
+    }
+}
+
+/// Read steghide's header using `password`, and describe the payload if there is one. Only the
+/// header is read: the payload itself is never extracted, even with the right password.
+fn steghide(image: &RgbaImage, password: &str) -> Option<String> {
+    let (width, height) = image.dimensions();
+    let mut order = SteghideOrder::new(password, width * height);
+
+    let parity = |position: u32| {
+        let pixel = image.get_pixel(position % width, height - 1 - position / width).0;
+        (pixel[0] ^ pixel[1] ^ pixel[2]) & 1
+    };
+    let mut read = |bits: usize| -> Option<u32> {
+        let mut value = 0;
+        for bit in 0..bits {
+            let mut sum = 0;
+            for _ in 0..STEGHIDE_SAMPLES_PER_BIT {
+                sum += parity(order.next()?);
+            }
+            value |= ((sum & 1) as u32) << bit;
+        }
+        Some(value)
+    };
+
+    if read(24)? != STEGHIDE_MAGIC {
+        return None;
+    }
+
+    // Only version 0 has ever been released
+    if read(1)? != 0 {
+        return None;
+    }
+
+    let algorithm = *STEGHIDE_ALGORITHMS.get(read(5)? as usize)?;
+    let mode = *STEGHIDE_MODES.get(read(3)? as usize)?;
+    let length = read(32)?;
+
+    let encryption = if algorithm == "none" {
+        String::from("unencrypted")
+    } else {
+        format!("encrypted with {algorithm} in {mode} mode")
+    };
+    Some(format!("{} bytes, {encryption}, detected only - extracting steghide payloads isn't supported", length.div_ceil(8)))
+}
+
+/// Known file signatures at the very start of the data.
+fn file_signature(bytes: &[u8]) -> Option<(&'static str, String)> {
+    let signatures: [(&[u8], &str); 9] = [
+        (b"\x89PNG\r\n\x1a\n", "PNG image"),
+        (b"\xff\xd8\xff", "JPEG image"),
+        (b"GIF8", "GIF image"),
+        (b"PK\x03\x04", "ZIP archive"),
+        (b"%PDF", "PDF document"),
+        (b"\x1f\x8b\x08", "gzip data"),
+        (b"BZh", "bzip2 data"),
+        (b"7z\xbc\xaf\x27\x1c", "7-Zip archive"),
+        (b"Rar!\x1a\x07", "RAR archive"),
+    ];
+
+    signatures.iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+        .map(|(_, kind)| ("file", kind.to_string()))
+}
+
+/// A run of printable text at the start of the data, like zsteg reports.
+fn text(bytes: &[u8]) -> Option<(&'static str, String)> {
+    let length = bytes.iter().take_while(|byte| is_printable(**byte)).count();
+
+    if length < MIN_TEXT_LENGTH {
+        return None;
+    }
+
+    Some(("text", format!("\"{}\"", preview(&bytes[..length]))))
+}
+
+fn is_printable(byte: u8) -> bool {
+    (0x20..0x7f).contains(&byte) || byte == b'\n' || byte == b'\r' || byte == b'\t'
+}
+
+/// The first part of some data as a single line of text for printing.
+fn preview(bytes: &[u8]) -> String {
+    let text: String = String::from_utf8_lossy(bytes).chars().take(64).collect();
+    text.escape_debug().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn noise(width: u32, height: u32) -> RgbaImage {
+        let mut rng = StdRng::seed_from_u64(30);
+        RgbaImage::from_fn(width, height, |_, _| image::Rgba([rng.gen(), rng.gen(), rng.gen(), 255]))
+    }
+
+    fn layout(name: &str) -> Layout {
+        layouts().into_iter().find(|layout| layout.to_string() == name).unwrap()
+    }
+
+    /// Write `bytes` into the image the way `extract` reads them back.
+    fn hide(image: &mut RgbaImage, layout: &Layout, bytes: &[u8]) {
+        let (width, height) = image.dimensions();
+        let mut bits = bytes.iter().flat_map(|byte| {
+            (0..8).map(move |i| if layout.msb_first { (byte >> (7 - i)) & 1 } else { (byte >> i) & 1 })
+        });
+
+        let positions: Vec<(u32, u32)> = if layout.column_major {
+            (0..width).flat_map(|x| (0..height).map(move |y| (x, y))).collect()
+        } else {
+            (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).collect()
+        };
+        for (x, y) in positions {
+            for channel in layout.channel_indices.iter() {
+                let Some(bit) = bits.next() else { return };
+                let value = &mut image.get_pixel_mut(x, y).0[*channel];
+                *value = (*value & !(1 << layout.bit)) | (bit << layout.bit);
+            }
+        }
+    }
+
+    fn kinds(image: &RgbaImage, password: &str) -> Vec<(String, &'static str)> {
+        scan(image, password).into_iter().map(|finding| (finding.layout, finding.kind)).collect()
+    }
+
+    #[test]
+    fn random_pixels_have_no_findings() {
+        assert!(scan(&noise(64, 64), "password").is_empty());
+    }
+
+    #[test]
+    fn finds_text_in_any_layout() {
+        for name in ["b0,rgb,msb,xy", "b1,bgr,lsb,yx", "b0,a,msb,yx"] {
+            let mut image = noise(64, 64);
+            hide(&mut image, &layout(name), b"The quick brown fox jumps over the lazy dog\0");
+
+            assert!(kinds(&image, "password").contains(&(name.to_string(), "text")), "{name}");
+        }
+    }
+
+    #[test]
+    fn finds_file_signatures() {
+        let mut image = noise(64, 64);
+        hide(&mut image, &layout("b0,rgb,msb,xy"), b"PK\x03\x04\x14\x00");
+
+        assert_eq!(kinds(&image, "password"), vec![(String::from("b0,rgb,msb,xy"), "file")]);
+    }
+
+    #[test]
+    fn decrypts_acetic_payloads() {
+        let mut payload = crate::START.as_bytes().to_vec();
+        payload.extend(crypt::encrypt(String::from("password"), String::from("hello")));
+        payload.extend(crate::END.as_bytes());
+
+        let mut image = noise(64, 64);
+        hide(&mut image, &layout("b0,r,msb,xy"), &payload);
+
+        let findings = scan(&image, "password");
+        assert_eq!(findings[0].kind, "acetic");
+        assert_eq!(findings[0].detail, "\"hello\"");
+        assert!(scan(&image, "wrong").iter().any(|finding| finding.detail.ends_with("wrong password")));
+    }
+
+    #[test]
+    fn reads_openstego_headers() {
+        let mut payload = b"OPENSTEGO\x02".to_vec();
+        payload.extend(5u32.to_le_bytes());
+        payload.extend([1, 8, 0, 0]);
+        payload.extend(b"note.txt");
+        payload.extend(b"hello");
+
+        let mut image = noise(64, 64);
+        hide(&mut image, &layout("b0,rgb,msb,xy"), &payload);
+
+        let findings = scan(&image, "password");
+        assert_eq!(findings[0].kind, "openstego");
+        assert_eq!(findings[0].detail, "file \"note.txt\", 5 bytes: \"hello\"");
+    }
+
+    #[test]
+    fn md5_matches_reference_digests() {
+        let hex = |digest: [u8; 16]| digest.iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+
+        assert_eq!(hex(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(b"The quick brown fox jumps over the lazy dog")), "9e107d9d372bb6826bd81d3542a419d6");
+        assert_eq!(hex(md5(&[b'a'; 100])), "36a92cc94a9e0fa21f625f8bfb007adf");
+    }
+
+    #[test]
+    fn steghide_order_is_a_permutation() {
+        let mut order: Vec<u32> = SteghideOrder::new("password", 1000).collect();
+        order.sort();
+
+        assert_eq!(order, (0..1000).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn finds_steghide_headers_with_the_password() {
+        let (width, height) = (64, 64);
+        let mut image = noise(width, height);
+
+        // Magic, version 0, rijndael-128 in cbc mode, 1000 bytes
+        let fields = [(STEGHIDE_MAGIC, 24), (0, 1), (2, 5), (1, 3), (8000, 32)];
+        let bits = fields.iter().flat_map(|(value, length)| (0..*length).map(move |bit| (value >> bit) & 1));
+        let mut order = SteghideOrder::new("password", width * height);
+
+        for bit in bits {
+            let positions: Vec<(u32, u32)> = (0..STEGHIDE_SAMPLES_PER_BIT)
+                .map(|_| order.next().unwrap())
+                .map(|position| (position % width, height - 1 - position / width))
+                .collect();
+            let parity: u32 = positions.iter()
+                .map(|(x, y)| image.get_pixel(*x, *y).0[..3].iter().fold(0, |parity, value| parity ^ value) as u32)
+                .sum();
+
+            if parity & 1 != bit {
+                let (x, y) = positions[0];
+                image.get_pixel_mut(x, y).0[0] ^= 1;
+            }
+        }
+
+        assert_eq!(
+            steghide(&image, "password"),
+            Some(String::from("1000 bytes, encrypted with rijndael-128 in cbc mode, detected only - extracting steghide payloads isn't supported"))
+        );
+        assert_eq!(steghide(&image, "wrong"), None);
+    }
+}
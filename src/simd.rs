@@ -0,0 +1,219 @@
+// -------------------------------------------------------------------------------------------------
+// Row kernels
+// -------------------------------------------------------------------------------------------------
+//
+// The innermost loops of convolution and of turning gradients into a magnitude and direction,
+// working on whole rows at a time. Built with the `simd` feature (nightly only) they use
+// `std::simd` eight lanes at a time, otherwise the plain loops below. Both multiply and add in
+// the same order so they give exactly the same result, except `hypot`, which can round an ulp
+// differently, and `atan2`, where the vector version is a polynomial within 4e-7 radians of the
+// exact angle.
+
+#[cfg(feature = "simd")]
+pub use vector::{abs_sum, atan2, hypot, multiply_add};
+
+#[cfg(not(feature = "simd"))]
+pub use scalar::{abs_sum, atan2, hypot, multiply_add};
+
+mod scalar {
+    /// `output += input * weight`, over the length of `output`.
+    pub fn multiply_add(output: &mut [f32], input: &[f32], weight: f32) {
+        for (value, input_value) in output.iter_mut().zip(input) {
+            *value += input_value * weight;
+        }
+    }
+
+    /// `sqrt(x^2 + y^2)` of each pair.
+    pub fn hypot(output: &mut [f32], x: &[f32], y: &[f32]) {
+        for (value, (x, y)) in output.iter_mut().zip(x.iter().zip(y)) {
+            *value = x.hypot(*y);
+        }
+    }
+
+    /// `|x| + |y|` of each pair.
+    pub fn abs_sum(output: &mut [f32], x: &[f32], y: &[f32]) {
+        for (value, (x, y)) in output.iter_mut().zip(x.iter().zip(y)) {
+            *value = x.abs() + y.abs();
+        }
+    }
+
+    /// Angle of each `(x, y)` from the x axis, from -pi to pi.
+    pub fn atan2(output: &mut [f32], y: &[f32], x: &[f32]) {
+        for (value, (y, x)) in output.iter_mut().zip(y.iter().zip(x)) {
+            *value = y.atan2(*x);
+        }
+    }
+}
+
+#[cfg(feature = "simd")]
+mod vector {
+    use std::f32::consts::{FRAC_PI_2, PI};
+    use std::simd::prelude::*;
+    use std::simd::StdFloat;
+
+    use super::scalar;
+
+    const LANES: usize = 8;
+
+    type Lanes = Simd<f32, LANES>;
+
+    /// Odd coefficients of a polynomial for `atan(x)` with `0 <= x <= 1` (Abramowitz and Stegun
+    /// 4.4.49).
+    const ATAN_COEFFICIENTS: [f32; 8] = [
+        0.999_999_3, -0.333_298_6, 0.199_465_4, -0.139_085_3,
+        0.096_420_04, -0.055_909_886, 0.021_861_23, -0.004_054_058,
+    ];
+
+    /// Where the rows stop filling whole vectors and the scalar loop has to take over.
+    fn split(length: usize) -> usize {
+        length - length % LANES
+    }
+
+    pub fn multiply_add(output: &mut [f32], input: &[f32], weight: f32) {
+        let end = split(output.len());
+        let (output, output_rest) = output.split_at_mut(end);
+        let weights = Lanes::splat(weight);
+
+        for (values, inputs) in output.chunks_exact_mut(LANES).zip(input.chunks_exact(LANES)) {
+            (Lanes::from_slice(values) + Lanes::from_slice(inputs) * weights).copy_to_slice(values);
+        }
+
+        scalar::multiply_add(output_rest, &input[end..], weight);
+    }
+
+    /// Apply `f` to each vector of `x` and `y`, finishing the rows with `rest`.
+    fn zip_lanes(
+        output: &mut [f32],
+        x: &[f32],
+        y: &[f32],
+        f: impl Fn(Lanes, Lanes) -> Lanes,
+        rest: fn(&mut [f32], &[f32], &[f32]),
+    ) {
+        let end = split(output.len());
+        let (output, output_rest) = output.split_at_mut(end);
+
+        for (values, (x, y)) in output.chunks_exact_mut(LANES).zip(x.chunks_exact(LANES).zip(y.chunks_exact(LANES))) {
+            f(Lanes::from_slice(x), Lanes::from_slice(y)).copy_to_slice(values);
+        }
+
+        rest(output_rest, &x[end..], &y[end..]);
+    }
+
+    /// There's no vector `hypot`, so this squares and adds directly, which can be an ulp away from
+    /// `f32::hypot`.
+    pub fn hypot(output: &mut [f32], x: &[f32], y: &[f32]) {
+        zip_lanes(output, x, y, |x, y| (x * x + y * y).sqrt(), scalar::hypot);
+    }
+
+    pub fn abs_sum(output: &mut [f32], x: &[f32], y: &[f32]) {
+        zip_lanes(output, x, y, |x, y| x.abs() + y.abs(), scalar::abs_sum);
+    }
+
+    /// The polynomial only covers the first eighth of the circle, so take the angle of the
+    /// smaller coordinate over the larger and reflect it into the right octant by the signs and
+    /// which coordinate was larger.
+    pub fn atan2(output: &mut [f32], y: &[f32], x: &[f32]) {
+        zip_lanes(output, y, x, |y, x| {
+            let (x_abs, y_abs) = (x.abs(), y.abs());
+            let larger = x_abs.simd_max(y_abs);
+            let zero = Lanes::splat(0.0);
+            let ratio = larger.simd_eq(zero).select(zero, x_abs.simd_min(y_abs) / larger);
+
+            let squared = ratio * ratio;
+            let mut polynomial = Lanes::splat(ATAN_COEFFICIENTS[7]);
+            for coefficient in ATAN_COEFFICIENTS[..7].iter().rev() {
+                polynomial = polynomial * squared + Lanes::splat(*coefficient);
+            }
+
+            let mut angle = polynomial * ratio;
+            angle = y_abs.simd_gt(x_abs).select(Lanes::splat(FRAC_PI_2) - angle, angle);
+            angle = x.is_sign_negative().select(Lanes::splat(PI) - angle, angle);
+            y.is_sign_negative().select(-angle, angle)
+        }, scalar::atan2);
+    }
+}
+
+
+// The same tests check whichever implementation is built, the plain loops on stable and the
+// vectors with the `simd` feature, against the formulas written out directly.
+#[cfg(test)]
+mod tests {
+    /// Lengths around one and two vectors, so both the vector loop and the scalar tail run.
+    const LENGTHS: [usize; 8] = [0, 1, 7, 8, 9, 15, 16, 21];
+
+    fn values(length: usize, seed: usize) -> Vec<f32> {
+        (0..length).map(|i| ((i * 37 + seed * 11) % 23) as f32 - 11.5).collect()
+    }
+
+    #[test]
+    fn multiply_add_is_exact() {
+        for length in LENGTHS {
+            let input = values(length + 3, 1);
+            let mut actual = values(length, 2);
+            let expected: Vec<f32> = actual.iter().zip(input.iter()).map(|(value, input)| value + input * 0.3).collect();
+
+            super::multiply_add(&mut actual, &input, 0.3);
+
+            assert_eq!(actual, expected, "length {length}");
+        }
+    }
+
+    #[test]
+    fn abs_sum_is_exact() {
+        for length in LENGTHS {
+            let (x, y) = (values(length, 3), values(length, 4));
+            let expected: Vec<f32> = x.iter().zip(y.iter()).map(|(x, y)| x.abs() + y.abs()).collect();
+            let mut actual = vec![0.0; length];
+
+            super::abs_sum(&mut actual, &x, &y);
+
+            assert_eq!(actual, expected, "length {length}");
+        }
+    }
+
+    #[test]
+    fn hypot_is_within_an_ulp() {
+        for length in LENGTHS {
+            let (x, y) = (values(length, 5), values(length, 6));
+            let mut actual = vec![0.0; length];
+
+            super::hypot(&mut actual, &x, &y);
+
+            for ((actual, x), y) in actual.iter().zip(x.iter()).zip(y.iter()) {
+                let expected = x.hypot(*y);
+                assert!((actual - expected).abs() <= expected * f32::EPSILON, "length {length}: {actual} != {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn atan2_is_accurate_around_the_circle() {
+        let length = 100_003;
+        let angles: Vec<f64> = (0..length).map(|i| i as f64 / length as f64 * std::f64::consts::TAU - std::f64::consts::PI).collect();
+        let radii: Vec<f32> = (0..length).map(|i| 1.0 + (i % 97) as f32).collect();
+        let y: Vec<f32> = angles.iter().zip(radii.iter()).map(|(angle, radius)| angle.sin() as f32 * radius).collect();
+        let x: Vec<f32> = angles.iter().zip(radii.iter()).map(|(angle, radius)| angle.cos() as f32 * radius).collect();
+
+        let mut actual = vec![0.0; length];
+        super::atan2(&mut actual, &y, &x);
+
+        for ((actual, y), x) in actual.iter().zip(y.iter()).zip(x.iter()) {
+            let exact = (*y as f64).atan2(*x as f64);
+            assert!((*actual as f64 - exact).abs() < 4e-7, "atan2({y}, {x}) = {actual}, expected {exact}");
+        }
+    }
+
+    #[test]
+    fn atan2_handles_axes_and_origin() {
+        let y = [0.0, 1.0, 0.0, -1.0, 0.0, 1.0, -1.0, -1.0, 2.0];
+        let x = [1.0, 0.0, -1.0, 0.0, 0.0, 1.0, -1.0, 1.0, -2.0];
+        let mut actual = vec![0.0; y.len()];
+
+        super::atan2(&mut actual, &y, &x);
+
+        for ((actual, y), x) in actual.iter().zip(y.iter()).zip(x.iter()) {
+            let expected = y.atan2(*x);
+            assert!((actual - expected).abs() < 4e-7, "atan2({y}, {x}) = {actual}, expected {expected}");
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use crate::convolution::{self, Border, Kernel};
+use crate::image_f32::ImageF32;
+
+// -------------------------------------------------------------------------------------------------
+// Syndrome-trellis codes
+// -------------------------------------------------------------------------------------------------
+//
+// Minimal-distortion embedding as described by Filler, Judas and Fridrich in "Minimizing Additive
+// Distortion in Steganography using Syndrome-Trellis Codes". The message is the syndrome of the
+// stego LSBs under a sparse parity-check matrix built by stacking a small `h x w` submatrix down
+// the diagonal. The Viterbi algorithm then finds the stego LSBs with the lowest total cost that
+// produce that syndrome.
+
+/// Constraint height of the code. 7 gives 128 trellis states which fits the Viterbi path of a
+/// single cover element into a `u128`.
+const CONSTRAINT_HEIGHT: usize = 7;
+const STATES: usize = 1 << CONSTRAINT_HEIGHT;
+
+/// Generate the columns of the `h x w` submatrix. Every column has its first and last row set,
+/// which is what the paper recommends for good coding efficiency. The generator is seeded by the
+/// width so the encoder and decoder always agree.
+fn submatrix(width: usize) -> Vec<usize> {
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15 ^ width as u64;
+    let mut columns = Vec::with_capacity(width);
+
+    for _ in 0..width {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+
+        let column = (state as usize & (STATES - 1)) | 1 | (1 << (CONSTRAINT_HEIGHT - 1));
+        columns.push(column);
+    }
+
+    columns
+}
+
+/// Mask for the rows of the submatrix that still fall inside the parity-check matrix. The last
+/// `h - 1` blocks are truncated at the bottom.
+fn row_mask(remaining_rows: usize) -> usize {
+    (1 << std::cmp::min(remaining_rows, CONSTRAINT_HEIGHT)) - 1
+}
+
+/// Width of the submatrix used for a message of `message_len` bits in `cover_len` elements.
+fn code_width(cover_len: usize, message_len: usize) -> usize {
+    cover_len / message_len
+}
+
+/// Embed `message` (one bit per byte) into the `cover` LSBs (one bit per byte) and return the
+/// stego LSBs. `costs` holds the cost of changing each cover element. Returns `None` if the
+/// message does not fit or every solution requires changing an element with infinite cost.
+pub fn embed(cover: &[u8], costs: &[f64], message: &[u8]) -> Option<Vec<u8>> {
+    if message.is_empty() {
+        return Some(cover.to_vec());
+    }
+
+    let width = code_width(cover.len(), message.len());
+    if width == 0 {
+        return None;
+    }
+
+    let columns = submatrix(width);
+    let mut path: Vec<u128> = vec![0; message.len() * width];
+    let mut weights = [f64::INFINITY; STATES];
+    weights[0] = 0.0;
+
+    // Forward pass
+    let mut index = 0;
+    for (block, bit) in message.iter().enumerate() {
+        let mask = row_mask(message.len() - block);
+
+        for column in columns.iter() {
+            let column = column & mask;
+            let (cost_zero, cost_one) = if cover[index] & 1 == 0 {
+                (0.0, costs[index])
+            } else {
+                (costs[index], 0.0)
+            };
+
+            let mut next = [f64::INFINITY; STATES];
+            let mut choices: u128 = 0;
+
+            for (state, weight) in next.iter_mut().enumerate() {
+                let keep = weights[state] + cost_zero;
+                let flip = weights[state ^ column] + cost_one;
+
+                if flip < keep {
+                    *weight = flip;
+                    choices |= 1 << state;
+                } else {
+                    *weight = keep;
+                }
+            }
+
+            path[index] = choices;
+            weights = next;
+            index += 1;
+        }
+
+        // Only states whose lowest bit matches the message bit survive, then move down a row
+        let mut shifted = [f64::INFINITY; STATES];
+        for state in 0..STATES / 2 {
+            shifted[state] = weights[2 * state + (bit & 1) as usize];
+        }
+        weights = shifted;
+    }
+
+    if !weights[0].is_finite() {
+        return None;
+    }
+
+    // Backward pass
+    let mut stego = cover.to_vec();
+    let mut state = 0;
+    for block in (0..message.len()).rev() {
+        state = (state << 1) | (message[block] & 1) as usize;
+        let mask = row_mask(message.len() - block);
+
+        for column in columns.iter().rev() {
+            index -= 1;
+            let bit = ((path[index] >> state) & 1) as u8;
+            stego[index] = bit;
+
+            if bit == 1 {
+                state ^= column & mask;
+            }
+        }
+    }
+
+    Some(stego)
+}
+
+/// Recover `message_len` bits from the stego LSBs by computing their syndrome.
+pub fn extract(stego: &[u8], message_len: usize) -> Vec<u8> {
+    let mut message = vec![0u8; message_len];
+    if message_len == 0 {
+        return message;
+    }
+
+    let width = code_width(stego.len(), message_len);
+    let columns = submatrix(width);
+
+    for block in 0..message_len {
+        for (j, column) in columns.iter().enumerate() {
+            if stego[block * width + j] & 1 == 0 {
+                continue;
+            }
+
+            for row in 0..CONSTRAINT_HEIGHT {
+                if (column >> row) & 1 == 1 && block + row < message_len {
+                    message[block + row] ^= 1;
+                }
+            }
+        }
+    }
+
+    message
+}
+
+// -------------------------------------------------------------------------------------------------
+// Distortion functions
+// -------------------------------------------------------------------------------------------------
+
+/// A cost function assigning every pixel the cost of changing it by one.
+pub trait Distortion {
+    fn costs(&self, channel: &ImageF32) -> ImageF32;
+}
+
+/// Every pixel costs the same, which makes STC behave like matrix embedding.
+pub struct Uniform;
+
+impl Distortion for Uniform {
+    fn costs(&self, channel: &ImageF32) -> ImageF32 {
+        ImageF32::filled(channel.width(), channel.height(), 1.0)
+    }
+}
+
+/// HILL (Li, Wang, Huang, Ni) - a high-pass residual spread out by two low-pass filters so that
+/// textured regions are cheap and smooth regions are expensive.
+pub struct Hill;
+
+impl Distortion for Hill {
+    fn costs(&self, channel: &ImageF32) -> ImageF32 {
+        let high_pass = Kernel::separable(vec![-0.5, 1.0, -0.5], vec![0.5, -1.0, 0.5]);
+
+        let mut residual = convolution::convolve(channel, &high_pass, Border::Reflect);
+        residual.map_in_place(f32::abs);
+
+        let mut suitability = convolution::convolve(&residual, &Kernel::box_filter(3), Border::Reflect);
+        suitability.map_in_place(|value| 1.0 / (value + 1e-10));
+
+        convolution::convolve(&suitability, &Kernel::box_filter(15), Border::Reflect)
+    }
+}
+
+/// Find a distortion function by name, as passed on the command line.
+pub fn distortion(name: &str) -> Box<dyn Distortion> {
+    match name {
+        "uniform" => Box::new(Uniform),
+        "hill" => Box::new(Hill),
+        _ => panic!("Unknown cost function {name}. Expected one of: hill, uniform")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 63) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn extract_recovers_embedded_message() {
+        let cover = bits(1, 1000);
+        let message = bits(2, 100);
+        let costs = vec![1.0; cover.len()];
+
+        let stego = embed(&cover, &costs, &message).unwrap();
+
+        assert_eq!(extract(&stego, message.len()), message);
+        // Coding should change fewer elements than writing the message out directly would
+        let changed = cover.iter().zip(stego.iter()).filter(|(a, b)| a != b).count();
+        assert!(changed < message.len() / 2, "{changed} elements changed");
+    }
+
+    #[test]
+    fn embed_avoids_infinite_costs() {
+        let cover = bits(3, 1000);
+        let message = bits(4, 50);
+        let costs: Vec<f64> = (0..cover.len()).map(|i| if i % 2 == 0 { f64::INFINITY } else { 1.0 }).collect();
+
+        let stego = embed(&cover, &costs, &message).unwrap();
+
+        assert_eq!(extract(&stego, message.len()), message);
+        assert!(cover.iter().zip(stego.iter()).step_by(2).all(|(a, b)| a == b));
+    }
+
+    #[test]
+    fn embed_rejects_messages_longer_than_the_cover() {
+        assert!(embed(&bits(5, 10), &[1.0; 10], &bits(6, 11)).is_none());
+    }
+
+    #[test]
+    fn hill_makes_smooth_regions_expensive() {
+        // Flat on the left, noisy on the right
+        let noise = bits(7, 64 * 64);
+        let channel = ImageF32::from_fn(64, 64, |x, y| if x < 32 { 128.0 } else { 255.0 * noise[y * 64 + x] as f32 });
+
+        let costs = Hill.costs(&channel);
+
+        assert!(costs[(8, 32)] > 10.0 * costs[(56, 32)]);
+    }
+}
@@ -0,0 +1,469 @@
+use image::{GenericImageView, RgbaImage};
+
+use crate::bitplane::CHANNEL_NAMES;
+use crate::png::PNG;
+
+/// Estimated embedding rates above this are reported as likely containing hidden data.
+const DETECTION_THRESHOLD: f64 = 0.05;
+
+/// Fewest pairs of values with enough samples for the chi-square attack to go on.
+const MIN_CATEGORIES: usize = 8;
+
+/// Results of every attack on a single channel.
+#[derive(Debug)]
+pub struct ChannelReport {
+    pub channel: &'static str,
+    /// Probability that the whole channel has been embedded into, from the chi-square attack
+    pub chi_square_p: f64,
+    /// Fraction of the channel, in pixel order, that the chi-square attack considers embedded
+    pub chi_square_rate: f64,
+    pub rs_rate: f64,
+    pub spa_rate: f64,
+}
+
+impl ChannelReport {
+    /// The best estimate of the embedding rate, taking the structural attacks over chi-square
+    /// since they also pick up randomly spread payloads.
+    pub fn estimated_rate(&self) -> f64 {
+        (self.rs_rate + self.spa_rate) / 2.0
+    }
+}
+
+/// Run the chi-square, RS and sample pair attacks over every colour channel of an image.
+pub fn analyze(image: &PNG) -> Vec<ChannelReport> {
+    let (width, height) = image.image.dimensions();
+    let rgba = image.image.to_rgba8();
+    let channel_count = if image.alpha { 4 } else { 3 };
+
+    (0..channel_count).map(|c| {
+        let values: Vec<u8> = rgba.pixels().map(|pixel| pixel.0[c]).collect();
+
+        ChannelReport {
+            channel: CHANNEL_NAMES[c],
+            chi_square_p: chi_square(&values),
+            chi_square_rate: chi_square_rate(&values),
+            rs_rate: rs_analysis(&values, width as usize, height as usize),
+            spa_rate: sample_pair_analysis(&values, width as usize, height as usize),
+        }
+    }).collect()
+}
+
+/// Format the reports as a table for printing.
+pub fn format_report(reports: &[ChannelReport]) -> String {
+    let mut lines = vec![String::from("Channel | Chi-square p | Chi-square rate | RS rate | SPA rate | Verdict")];
+
+    for report in reports.iter() {
+        let verdict = if report.estimated_rate() > DETECTION_THRESHOLD || report.chi_square_p > 0.95 {
+            "Suspicious"
+        } else {
+            "Clean"
+        };
+
+        lines.push(format!(
+            "{:<7} | {:>12.4} | {:>15.4} | {:>7.4} | {:>8.4} | {}",
+            report.channel, report.chi_square_p, report.chi_square_rate, report.rs_rate, report.spa_rate, verdict
+        ));
+    }
+
+    lines.join("\n")
+}
+
+/// Stretch the LSB of every colour channel to 0 or 255 so that any structure in it becomes
+/// visible. Alpha is made opaque so that the plane can be viewed.
+pub fn lsb_plane(image: &PNG) -> RgbaImage {
+    let mut plane = image.image.to_rgba8();
+
+    for pixel in plane.pixels_mut() {
+        for c in 0..3 {
+            pixel.0[c] = (pixel.0[c] & 1) * 255;
+        }
+        pixel.0[3] = 255;
+    }
+
+    plane
+}
+
+// -------------------------------------------------------------------------------------------------
+// Chi-square attack
+// -------------------------------------------------------------------------------------------------
+
+/// Westfeld and Pfitzmann's attack. LSB replacement evens out the counts of each pair of values
+/// 2k and 2k+1, so a good fit to the averaged histogram means something has been embedded. The
+/// result is the probability of embedding.
+fn chi_square(values: &[u8]) -> f64 {
+    let mut histogram = [0u64; 256];
+    for value in values.iter() {
+        histogram[*value as usize] += 1;
+    }
+
+    chi_square_histogram(&histogram).unwrap_or(0.0)
+}
+
+/// Probability of embedding from a histogram, or `None` if too few pairs have enough samples to
+/// tell.
+fn chi_square_histogram(histogram: &[u64; 256]) -> Option<f64> {
+    let mut statistic = 0.0;
+    let mut categories = 0;
+
+    for pair in histogram.chunks(2) {
+        let expected = (pair[0] + pair[1]) as f64 / 2.0;
+
+        // Categories with very few samples make the statistic unreliable
+        if expected <= 4.0 {
+            continue;
+        }
+
+        statistic += (pair[0] as f64 - expected).powi(2) / expected;
+        categories += 1;
+    }
+
+    if categories < MIN_CATEGORIES {
+        return None;
+    }
+
+    Some(1.0 - regularized_gamma((categories - 1) as f64 / 2.0, statistic / 2.0))
+}
+
+/// Sequential chi-square: run the attack over increasing prefixes of the image and report the
+/// fraction up to which the embedding probability stays high. Sequential embedders like `lsb`
+/// show up as a long run from the start of the image.
+fn chi_square_rate(values: &[u8]) -> f64 {
+    let steps = 100;
+    let mut histogram = [0u64; 256];
+    let mut rate = 0.0;
+    let mut start = 0;
+
+    for step in 1..=steps {
+        let end = values.len() * step / steps;
+        for value in values[start..end].iter() {
+            histogram[*value as usize] += 1;
+        }
+        start = end;
+
+        // The first few prefixes of a small image may not have enough samples to go on yet
+        match chi_square_histogram(&histogram) {
+            Some(probability) if probability < 0.5 => break,
+            Some(_) => rate = step as f64 / steps as f64,
+            None => continue,
+        }
+    }
+
+    rate
+}
+
+/// Lanczos approximation of ln(Γ(x))
+fn ln_gamma(x: f64) -> f64 {
+    let coefficients = [
+        76.180_091_729_471_46,
+        -86.505_320_329_416_77,
+        24.014_098_240_830_91,
+        -1.231_739_572_450_155,
+        0.001_208_650_973_866_179,
+        -0.000_005_395_239_384_953,
+    ];
+
+    let mut y = x;
+    let tmp = x + 5.5;
+    let tmp = tmp - (x + 0.5) * tmp.ln();
+    let mut series = 1.000_000_000_190_015;
+    for coefficient in coefficients.iter() {
+        y += 1.0;
+        series += coefficient / y;
+    }
+
+    -tmp + (2.506_628_274_631_000_5 * series / x).ln()
+}
+
+/// The regularized lower incomplete gamma function P(a, x), which is the CDF of the chi-square
+/// distribution with 2a degrees of freedom at 2x.
+fn regularized_gamma(a: f64, x: f64) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+
+    let prefix = -x + a * x.ln() - ln_gamma(a);
+
+    if x < a + 1.0 {
+        // Series expansion
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        let mut n = a;
+        for _ in 0..1000 {
+            n += 1.0;
+            term *= x / n;
+            sum += term;
+            if term.abs() < sum.abs() * 1e-12 {
+                break;
+            }
+        }
+        (sum.ln() + prefix).exp()
+    } else {
+        // Continued fraction (modified Lentz) for Q(a, x)
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-12 {
+                break;
+            }
+        }
+        1.0 - (prefix.exp() * h)
+    }
+}
+
+// -------------------------------------------------------------------------------------------------
+// RS analysis
+// -------------------------------------------------------------------------------------------------
+
+const RS_MASK: [i32; 4] = [0, 1, 1, 0];
+
+/// Regular and singular group counts for the positive and negative masks.
+struct RsCounts {
+    regular: f64,
+    singular: f64,
+    negative_regular: f64,
+    negative_singular: f64,
+}
+
+/// Flip LSBs: 0 <-> 1, 2 <-> 3, ...
+fn flip_positive(value: i32) -> i32 {
+    value ^ 1
+}
+
+/// Shifted flip: -1 <-> 0, 1 <-> 2, ...
+fn flip_negative(value: i32) -> i32 {
+    flip_positive(value + 1) - 1
+}
+
+/// Smoothness of a group of pixels. Noise added by flipping tends to increase it.
+fn discrimination(group: &[i32]) -> i32 {
+    group.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum()
+}
+
+fn rs_counts(values: &[i32], width: usize, height: usize) -> RsCounts {
+    let mut counts = RsCounts { regular: 0.0, singular: 0.0, negative_regular: 0.0, negative_singular: 0.0 };
+    let mut groups = 0.0;
+    let size = RS_MASK.len();
+
+    for y in 0..height {
+        for x in (0..width - width % size).step_by(size) {
+            let group = &values[y * width + x..y * width + x + size];
+            let original = discrimination(group);
+
+            let mut positive = [0; 4];
+            let mut negative = [0; 4];
+            for i in 0..size {
+                positive[i] = if RS_MASK[i] == 1 { flip_positive(group[i]) } else { group[i] };
+                negative[i] = if RS_MASK[i] == 1 { flip_negative(group[i]) } else { group[i] };
+            }
+
+            match discrimination(&positive).cmp(&original) {
+                std::cmp::Ordering::Greater => counts.regular += 1.0,
+                std::cmp::Ordering::Less => counts.singular += 1.0,
+                std::cmp::Ordering::Equal => {}
+            }
+            match discrimination(&negative).cmp(&original) {
+                std::cmp::Ordering::Greater => counts.negative_regular += 1.0,
+                std::cmp::Ordering::Less => counts.negative_singular += 1.0,
+                std::cmp::Ordering::Equal => {}
+            }
+            groups += 1.0;
+        }
+    }
+
+    if groups > 0.0 {
+        counts.regular /= groups;
+        counts.singular /= groups;
+        counts.negative_regular /= groups;
+        counts.negative_singular /= groups;
+    }
+
+    counts
+}
+
+/// Fridrich, Goljan and Du's RS steganalysis. Compares how regular and singular groups react to
+/// flipping with a positive and negative mask, before and after flipping every LSB, and solves
+/// for the embedding rate.
+fn rs_analysis(values: &[u8], width: usize, height: usize) -> f64 {
+    if width < RS_MASK.len() {
+        return 0.0;
+    }
+
+    let original: Vec<i32> = values.iter().map(|value| *value as i32).collect();
+    let flipped: Vec<i32> = original.iter().map(|value| flip_positive(*value)).collect();
+
+    let before = rs_counts(&original, width, height);
+    let after = rs_counts(&flipped, width, height);
+
+    let d0 = before.regular - before.singular;
+    let d1 = after.regular - after.singular;
+    let n0 = before.negative_regular - before.negative_singular;
+    let n1 = after.negative_regular - after.negative_singular;
+
+    let a = 2.0 * (d1 + d0);
+    let b = n0 - n1 - d1 - 3.0 * d0;
+    let c = d0 - n0;
+
+    let z = match smallest_root(a, b, c) {
+        Some(z) => z,
+        None => return 0.0,
+    };
+
+    (z / (z - 0.5)).clamp(0.0, 1.0)
+}
+
+// -------------------------------------------------------------------------------------------------
+// Sample pair analysis
+// -------------------------------------------------------------------------------------------------
+
+/// Dumitrescu, Wu and Wang's sample pair analysis over horizontally and vertically adjacent
+/// pixels.
+fn sample_pair_analysis(values: &[u8], width: usize, height: usize) -> f64 {
+    let mut x = 0.0;
+    let mut y = 0.0;
+    let mut k = 0.0;
+    let mut pairs = 0.0;
+
+    let mut count = |r: u8, s: u8| {
+        let even = s & 1 == 0;
+        if (even && r < s) || (!even && r > s) {
+            x += 1.0;
+        }
+        if (even && r > s) || (!even && r < s) {
+            y += 1.0;
+        }
+        if r / 2 == s / 2 {
+            k += 1.0;
+        }
+        pairs += 1.0;
+    };
+
+    for row in 0..height {
+        for col in 0..width {
+            let index = row * width + col;
+            if col + 1 < width {
+                count(values[index], values[index + 1]);
+            }
+            if row + 1 < height {
+                count(values[index], values[index + width]);
+            }
+        }
+    }
+
+    if k == 0.0 {
+        return 0.0;
+    }
+
+    let a = 2.0 * k;
+    let b = 2.0 * (2.0 * x - pairs);
+    let c = y - x;
+
+    // The root is the fraction of pixels that were changed, which is half of those embedded into
+    match smallest_root(a, b, c) {
+        Some(changed) => (2.0 * changed).clamp(0.0, 1.0),
+        None => 0.0,
+    }
+}
+
+/// Root of `a x^2 + b x + c` with the smallest magnitude, or `None` if there are no real roots.
+fn smallest_root(a: f64, b: f64, c: f64) -> Option<f64> {
+    if a.abs() < f64::EPSILON {
+        if b.abs() < f64::EPSILON {
+            return None;
+        }
+        return Some(-c / b);
+    }
+
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    let plus = (-b + discriminant.sqrt()) / (2.0 * a);
+    let minus = (-b - discriminant.sqrt()) / (2.0 * a);
+
+    if plus.abs() < minus.abs() {
+        Some(plus)
+    } else {
+        Some(minus)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    const SIZE: usize = 128;
+
+    /// Smooth shading with a little sensor noise, contrast stretched after quantizing like an
+    /// edited photo so the histogram has gaps.
+    fn cover() -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(28);
+        (0..SIZE * SIZE).map(|i| {
+            let (x, y) = ((i % SIZE) as f64, (i / SIZE) as f64);
+            let noise: f64 = (0..4).map(|_| rng.gen_range(-1.5..1.5)).sum();
+            let value = (128.0 + 50.0 * (x / 13.0).sin() * (y / 17.0).cos() + noise).round();
+            (value * 1.3 - 38.0).round() as u8
+        }).collect()
+    }
+
+    /// Replace the LSBs of the first `fraction` of the values with random bits.
+    fn embed(values: &[u8], fraction: f64) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(29);
+        let end = (values.len() as f64 * fraction) as usize;
+        values.iter().enumerate()
+            .map(|(i, value)| if i < end { (value & !1) | rng.gen::<u8>() & 1 } else { *value })
+            .collect()
+    }
+
+    #[test]
+    fn clean_cover_is_not_flagged() {
+        let values = cover();
+
+        assert!(chi_square(&values) < 0.5);
+        assert!(chi_square_rate(&values) < 0.1);
+        assert!(rs_analysis(&values, SIZE, SIZE) < 0.05);
+        assert!(sample_pair_analysis(&values, SIZE, SIZE) < 0.05);
+    }
+
+    #[test]
+    fn structural_attacks_estimate_the_embedding_rate() {
+        let values = embed(&cover(), 0.5);
+
+        assert!((rs_analysis(&values, SIZE, SIZE) - 0.5).abs() < 0.15);
+        assert!((sample_pair_analysis(&values, SIZE, SIZE) - 0.5).abs() < 0.15);
+    }
+
+    #[test]
+    fn sequential_chi_square_finds_where_embedding_stops() {
+        let values = embed(&cover(), 0.5);
+
+        assert!((chi_square_rate(&values) - 0.5).abs() < 0.1);
+        assert!(chi_square(&embed(&cover(), 1.0)) > 0.95);
+    }
+
+    #[test]
+    fn regularized_gamma_matches_exponential_cdf() {
+        // P(1, x) = 1 - e^-x
+        for x in [0.1, 1.0, 5.0] {
+            assert!((regularized_gamma(1.0, x) - (1.0 - (-x).exp())).abs() < 1e-9);
+        }
+    }
+}